@@ -1,11 +1,20 @@
-use crate::{Error, Receiver};
-use log::LevelFilter;
-use std::io::{stderr, stdout, BufWriter, Stderr, Stdout, Write};
-use std::{fmt, fs::File};
+use crate::stats::OutputerCounters;
+use crate::{Error, Event, Filter, Formater, Message, Receiver};
+use crossbeam_channel::RecvTimeoutError;
+use log::{Level, LevelFilter};
+use std::io::{self, stderr, stdout, BufWriter, Stderr, Stdout, Write};
+use std::ops::RangeInclusive;
+use std::sync::{atomic::Ordering, Arc};
+use std::time::{Duration, Instant};
+use std::{fmt, fs::File, thread};
 
 pub trait Consumer: Send + Sync + 'static {
     fn boxed(self) -> Result<Box<dyn Consumer>, Error>;
     fn consume(&mut self, channel: Receiver);
+    /// whether it needs `Message::record`, makes `NonblockLogger` structured
+    fn structured(&self) -> bool {
+        false
+    }
 }
 
 impl Consumer for BaseConsumer {
@@ -13,18 +22,60 @@ impl Consumer for BaseConsumer {
         Ok(Box::new(self) as _)
     }
 
+    fn structured(&self) -> bool {
+        self.outputers.iter().any(|o| o.formater.is_some() || o.filter.is_some())
+    }
+
     fn consume(&mut self, channel: Receiver) {
-        for message in channel {
-            if let Some(message) = message {
-                for (level, ref mut w) in self.outputers.iter_mut() {
-                    if *level >= message.level {
-                        if let Err(e) = w.write_all(message.content.as_bytes()) {
-                            panic!("failed write log to {}: {}", (&*w).desc(), e);
-                        }
+        let g = channel.logger();
+        for o in self.outputers.iter() {
+            g.counters().register(o.outputer.desc(), o.counters.clone());
+        }
+
+        // written but not flushed yet
+        let mut dirty = false;
+        let mut flushed = Instant::now();
+
+        loop {
+            let event = match self.flush_interval {
+                Some(interval) if dirty => match channel.recv_timeout(interval.saturating_sub(flushed.elapsed())) {
+                    Ok(event) => Some(event),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                },
+                _ => match channel.recv() {
+                    Ok(event) => Some(event),
+                    Err(_) => break,
+                },
+            };
+
+            match event {
+                Some(Event::Message(message)) => {
+                    self.write(&message);
+                    dirty = true;
+
+                    if self.flush_level.map(|l| message.level <= l).unwrap_or(false) {
+                        self.flush();
+                        dirty = false;
+                        flushed = Instant::now();
                     }
+                    channel.recycle(message);
+                }
+                Some(Event::Reopen) => self.reopen(),
+                Some(Event::Flush(done)) => {
+                    self.flush();
+                    dirty = false;
+                    flushed = Instant::now();
+                    done.send(()).ok();
                 }
-            } else {
-                break;
+                Some(Event::Exit) => break,
+                None => {}
+            }
+
+            if dirty && self.flush_interval.map(|i| flushed.elapsed() >= i).unwrap_or(false) {
+                self.flush();
+                dirty = false;
+                flushed = Instant::now();
             }
         }
     }
@@ -32,7 +83,12 @@ impl Consumer for BaseConsumer {
 
 #[derive(Default)]
 pub struct BaseConsumer {
-    outputers: Vec<(LevelFilter, Box<dyn Outputer>)>,
+    outputers: Vec<Output>,
+    flush_interval: Option<Duration>,
+    flush_level: Option<Level>,
+    on_error: Option<Box<dyn FnMut(Error) + Send + Sync + 'static>>,
+    // for the formaters of the outputers
+    buf: String,
 }
 
 impl fmt::Debug for BaseConsumer {
@@ -40,8 +96,14 @@ impl fmt::Debug for BaseConsumer {
         fmt.debug_struct("BaseConsumer")
             .field(
                 "outputers",
-                &self.outputers.iter().map(|(l, o)| (l, o.desc())).collect::<Vec<_>>(),
+                &self
+                    .outputers
+                    .iter()
+                    .map(|o| (o.severest, o.level, o.outputer.desc(), &o.policy))
+                    .collect::<Vec<_>>(),
             )
+            .field("flush_interval", &self.flush_interval)
+            .field("flush_level", &self.flush_level)
             .finish()
     }
 }
@@ -52,10 +114,77 @@ impl BaseConsumer {
     }
 
     pub fn chain<O: Outputer>(mut self, level: LevelFilter, outputer: O) -> Result<Self, Error> {
-        self.outputers.push((level, outputer.boxed()?));
+        self.outputers.push(Output::new(level, outputer.boxed()?));
         Ok(self)
     }
 
+    /// chain an outputer only for the levels in the range, such as `Level::Trace..=Level::Info`, in any order
+    pub fn chain_range<O: Outputer>(mut self, levels: RangeInclusive<Level>, outputer: O) -> Result<Self, Error> {
+        let (start, end) = levels.into_inner();
+        let mut output = Output::new(start.max(end).to_level_filter(), outputer.boxed()?);
+        output.severest = start.min(end);
+
+        self.outputers.push(output);
+        Ok(self)
+    }
+
+    /// set the write-error policy of the last chained outputer
+    pub fn policy(mut self, policy: ErrorPolicy) -> Self {
+        if let Some(o) = self.outputers.last_mut() {
+            o.policy = policy;
+        }
+        self
+    }
+
+    /// render the messages for the last chained outputer by its own formater, instead of the logger's,
+    /// the messages without records are skipped
+    pub fn formater<F: Formater>(mut self, formater: F) -> Self {
+        if let Some(o) = self.outputers.last_mut() {
+            o.formater = Some(formater.boxed());
+        }
+        self
+    }
+
+    /// write only the records accepted by the filter to the last chained outputer, such as a `BaseFilter` of some targets,
+    /// the messages without records are skipped
+    pub fn filter<F: Filter>(mut self, filter: F) -> Result<Self, Error> {
+        if let Some(o) = self.outputers.last_mut() {
+            o.filter = Some(filter.boxed()?);
+        }
+        Ok(self)
+    }
+
+    /// handle the errors of the outputers, print them to stderr default
+    pub fn on_error<F>(mut self, on_error: F) -> Self
+    where
+        F: FnMut(Error) + Send + Sync + 'static,
+    {
+        self.on_error = Some(Box::new(on_error) as _);
+        self
+    }
+
+    /// flush the outputers at this interval if anything written, such as 200ms for `BufWriter`
+    pub fn flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = Some(interval);
+        self
+    }
+
+    #[inline]
+    pub fn flush_interval_get(&self) -> Option<Duration> {
+        self.flush_interval
+    }
+
+    /// flush the outputers immediately after the messages at or above this level
+    pub fn flush_level(mut self, level: Level) -> Self {
+        self.flush_level = Some(level);
+        self
+    }
+
+    #[inline]
+    pub fn flush_level_get(&self) -> Option<Level> {
+        self.flush_level
+    }
+
     pub fn stdout(level: LevelFilter) -> Self {
         Self::new().chain(level, stdout()).unwrap()
     }
@@ -74,11 +203,233 @@ impl BaseConsumer {
     {
         Self::new().chain(level, bufwriter).unwrap()
     }
+
+    fn write(&mut self, message: &Message) {
+        let on_error = &mut self.on_error;
+        let mut report = |e| report(on_error, e);
+        let buf = &mut self.buf;
+
+        for o in self.outputers.iter_mut().filter(|o| !o.disabled) {
+            if o.accept(message) {
+                match (o.formater.as_ref(), message.record.as_ref()) {
+                    (Some(f), Some(record)) => {
+                        buf.clear();
+                        record.with_record(|r| f.format_into(r, buf));
+                        o.write(buf.as_bytes(), &mut report)
+                    }
+                    // not rendered by the formater, such as sent by a custom `sendfn`
+                    (Some(_), None) => {}
+                    (None, _) => o.write(message.content.as_bytes(), &mut report),
+                }
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        let on_error = &mut self.on_error;
+        let mut report = |e| report(on_error, e);
+
+        for o in self.outputers.iter_mut().filter(|o| !o.disabled) {
+            o.flush(&mut report);
+        }
+    }
+
+    fn reopen(&mut self) {
+        let on_error = &mut self.on_error;
+        let mut report = |e| report(on_error, e);
+
+        for o in self.outputers.iter_mut() {
+            o.reopen(&mut report);
+        }
+    }
+}
+
+fn report(on_error: &mut Option<Box<dyn FnMut(Error) + Send + Sync + 'static>>, error: Error) {
+    match on_error {
+        Some(f) => f(error),
+        None => eprintln!("nonblock-logger: {}", error),
+    }
+}
+
+/// What `BaseConsumer` does after an outputer failed to write a message
+#[derive(Default)]
+pub enum ErrorPolicy {
+    /// panic the consumer thread, the default
+    #[default]
+    Panic,
+    /// drop the message silently, the errors are still counted
+    Ignore,
+    /// drop the message and report the error to `on_error`, or stderr default
+    Report,
+    /// retry after the backoff, which doubles for each time, drop the message at last
+    Retry { times: usize, backoff: Duration },
+    /// drop the message and stop writing to the outputer, until the outputer reopened
+    Disable,
+    /// write the message to another outputer, such as stderr
+    Fallback(Box<dyn Outputer>),
+}
+
+impl ErrorPolicy {
+    pub fn fallback<O: Outputer>(outputer: O) -> Result<Self, Error> {
+        outputer.boxed().map(ErrorPolicy::Fallback)
+    }
+}
+
+impl fmt::Debug for ErrorPolicy {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorPolicy::Panic => fmt.write_str("Panic"),
+            ErrorPolicy::Ignore => fmt.write_str("Ignore"),
+            ErrorPolicy::Report => fmt.write_str("Report"),
+            ErrorPolicy::Retry { times, backoff } => fmt
+                .debug_struct("Retry")
+                .field("times", times)
+                .field("backoff", backoff)
+                .finish(),
+            ErrorPolicy::Disable => fmt.write_str("Disable"),
+            ErrorPolicy::Fallback(o) => fmt.debug_tuple("Fallback").field(&o.desc()).finish(),
+        }
+    }
+}
+
+struct Output {
+    level: LevelFilter,
+    // the most severe level written
+    severest: Level,
+    outputer: Box<dyn Outputer>,
+    formater: Option<Box<dyn Formater>>,
+    filter: Option<Box<dyn Filter>>,
+    policy: ErrorPolicy,
+    counters: Arc<OutputerCounters>,
+    disabled: bool,
+}
+
+impl Output {
+    fn new(level: LevelFilter, outputer: Box<dyn Outputer>) -> Self {
+        Self {
+            level,
+            severest: Level::Error,
+            outputer,
+            formater: None,
+            filter: None,
+            policy: ErrorPolicy::default(),
+            counters: Arc::default(),
+            disabled: false,
+        }
+    }
+
+    fn accept(&self, message: &Message) -> bool {
+        if self.level < message.level || self.severest > message.level {
+            return false;
+        }
+
+        match (self.filter.as_ref(), message.record.as_ref()) {
+            (Some(f), Some(record)) => record.with_record(|r| f.log(r)),
+            // no target to filter, such as sent by a custom `sendfn`
+            (Some(_), None) => false,
+            (None, _) => true,
+        }
+    }
+
+    fn write(&mut self, content: &[u8], report: &mut dyn FnMut(Error)) {
+        self.write_content(content, report);
+        self.take_errors(report);
+    }
+
+    fn write_content(&mut self, content: &[u8], report: &mut dyn FnMut(Error)) {
+        let mut error = match self.outputer.write_all(content) {
+            Ok(()) => return self.written(content),
+            Err(e) => e,
+        };
+        let errors = self.error();
+
+        match &mut self.policy {
+            ErrorPolicy::Panic => panic!("failed write log to {}: {}", self.outputer.desc(), error),
+            ErrorPolicy::Ignore => return,
+            ErrorPolicy::Report => {}
+            ErrorPolicy::Retry { times, backoff } => {
+                let mut backoff = *backoff;
+                for _ in 0..*times {
+                    thread::sleep(backoff);
+                    backoff *= 2;
+
+                    match self.outputer.write_all(content) {
+                        Ok(()) => return self.written(content),
+                        Err(e) => error = e,
+                    }
+                }
+            }
+            ErrorPolicy::Disable => self.disabled = true,
+            ErrorPolicy::Fallback(fallback) => {
+                if let Err(e) = fallback.write_all(content) {
+                    report(Error::output(fallback.desc(), errors, e));
+                }
+            }
+        }
+
+        report(Error::output(self.outputer.desc(), errors, error));
+    }
+
+    fn take_errors(&mut self, report: &mut dyn FnMut(Error)) {
+        while let Some(e) = self.outputer.take_error() {
+            self.report(report, Error::output(self.outputer.desc(), self.error(), e));
+        }
+    }
+
+    // nothing reported with `ErrorPolicy::Ignore`
+    fn report(&self, report: &mut dyn FnMut(Error), error: Error) {
+        if !matches!(self.policy, ErrorPolicy::Ignore) {
+            report(error)
+        }
+    }
+
+    #[inline]
+    fn written(&self, content: &[u8]) {
+        self.counters.written.fetch_add(content.len() as _, Ordering::Relaxed);
+    }
+
+    // returns the count of errors
+    #[inline]
+    fn error(&self) -> u64 {
+        self.counters.errors.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn flush(&mut self, report: &mut dyn FnMut(Error)) {
+        if let Err(e) = self.outputer.flush() {
+            self.report(report, Error::output(self.outputer.desc(), self.error(), e));
+        }
+        self.take_errors(report);
+
+        if let ErrorPolicy::Fallback(fallback) = &mut self.policy {
+            if let Err(e) = fallback.flush() {
+                report(Error::output(
+                    fallback.desc(),
+                    self.counters.errors.load(Ordering::Relaxed),
+                    e,
+                ));
+            }
+        }
+    }
+
+    fn reopen(&mut self, report: &mut dyn FnMut(Error)) {
+        match self.outputer.reopen() {
+            Ok(()) => self.disabled = false,
+            Err(e) => self.report(report, Error::output(self.outputer.desc(), self.error(), e)),
+        }
+    }
 }
 
 pub trait Outputer: Write + Send + Sync + 'static {
     fn boxed(self) -> Result<Box<dyn Outputer>, Error>;
     fn desc(&self) -> &str;
+    /// reopen the underlying file by its path, does nothing default
+    fn reopen(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+    /// an error off the writes, such as cleaning or compressing the old files, reported by `BaseConsumer`
+    fn take_error(&mut self) -> Option<io::Error> {
+        None
+    }
 }
 
 impl Outputer for Stdout {
@@ -123,3 +474,207 @@ where
         "bufwriter"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BaseFilter, OwnedRecord};
+    use std::sync::Mutex;
+
+    // fails the first writes, writes the rest into the lines
+    #[derive(Debug, Default)]
+    struct State {
+        fails: usize,
+        attempts: usize,
+        lines: Vec<String>,
+        error: Option<io::Error>,
+    }
+
+    #[derive(Debug, Clone, Default)]
+    struct Flaky(Arc<Mutex<State>>);
+
+    impl Flaky {
+        fn failing(fails: usize) -> Self {
+            let flaky = Self::default();
+            flaky.0.lock().unwrap().fails = fails;
+            flaky
+        }
+
+        fn state<T>(&self, f: impl FnOnce(&mut State) -> T) -> T {
+            f(&mut self.0.lock().unwrap())
+        }
+    }
+
+    impl Write for Flaky {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut state = self.0.lock().unwrap();
+            state.attempts += 1;
+            if state.fails > 0 {
+                state.fails -= 1;
+                return Err(io::Error::other("flaky"));
+            }
+            state.lines.push(String::from_utf8_lossy(buf).into_owned());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Outputer for Flaky {
+        fn boxed(self) -> Result<Box<dyn Outputer>, Error> {
+            Ok(Box::new(self) as _)
+        }
+
+        fn desc(&self) -> &str {
+            "flaky"
+        }
+
+        fn reopen(&mut self) -> io::Result<()> {
+            self.0.lock().unwrap().fails = 0;
+            Ok(())
+        }
+
+        fn take_error(&mut self) -> Option<io::Error> {
+            self.0.lock().unwrap().error.take()
+        }
+    }
+
+    fn flaky_consumer(flaky: &Flaky, policy: ErrorPolicy) -> (BaseConsumer, Arc<Mutex<Vec<String>>>) {
+        let reported = Arc::new(Mutex::new(vec![]));
+        let r = reported.clone();
+        let consumer = BaseConsumer::new()
+            .chain(LevelFilter::Trace, flaky.clone())
+            .unwrap()
+            .policy(policy)
+            .on_error(move |e| r.lock().unwrap().push(e.to_string()));
+        (consumer, reported)
+    }
+
+    fn write(consumer: &mut BaseConsumer, content: &str) {
+        consumer.write(&Message::new(content.to_owned(), Level::Info));
+    }
+
+    #[test]
+    fn accept_the_level_ranges() {
+        let consumer = BaseConsumer::new()
+            .chain_range(Level::Trace..=Level::Info, Flaky::default())
+            .unwrap()
+            .chain_range(Level::Error..=Level::Warn, Flaky::default())
+            .unwrap();
+
+        for level in [Level::Error, Level::Warn, Level::Info, Level::Debug, Level::Trace] {
+            let message = Message::new(String::new(), level);
+            let accepted = consumer.outputers.iter().map(|o| o.accept(&message)).collect::<Vec<_>>();
+            assert_eq!(accepted, [level >= Level::Info, level <= Level::Warn], "{}", level);
+        }
+    }
+
+    #[test]
+    fn filter_rejects_the_record_less() {
+        let mut output = Output::new(LevelFilter::Trace, Flaky::default().boxed().unwrap());
+        output.filter = Some(BaseFilter::new().chain("audit", LevelFilter::Off).boxed().unwrap());
+
+        let message = |target| {
+            let record = log::Record::builder().level(Level::Info).target(target).build();
+            Message::structured(String::new(), OwnedRecord::capture(&record))
+        };
+        assert!(output.accept(&message("app")));
+        assert!(!output.accept(&message("audit")));
+        assert!(!output.accept(&Message::new(String::new(), Level::Info)));
+    }
+
+    fn errors(consumer: &BaseConsumer) -> u64 {
+        consumer.outputers[0].counters.errors.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn retry_until_written() {
+        let retry = |times| ErrorPolicy::Retry {
+            times,
+            backoff: Duration::from_millis(1),
+        };
+
+        let flaky = Flaky::failing(2);
+        let (mut consumer, reported) = flaky_consumer(&flaky, retry(2));
+        write(&mut consumer, "1");
+        assert_eq!(flaky.state(|s| (s.attempts, s.lines.clone())), (3, vec!["1".to_owned()]));
+        assert_eq!(errors(&consumer), 1);
+        assert!(reported.lock().unwrap().is_empty());
+
+        let flaky = Flaky::failing(3);
+        let (mut consumer, reported) = flaky_consumer(&flaky, retry(2));
+        write(&mut consumer, "1");
+        assert_eq!(flaky.state(|s| (s.attempts, s.lines.len())), (3, 0));
+        assert_eq!(*reported.lock().unwrap(), ["outputer flaky failed (1 errors): flaky"]);
+    }
+
+    #[test]
+    fn disable_until_reopened() {
+        let flaky = Flaky::failing(1);
+        let (mut consumer, reported) = flaky_consumer(&flaky, ErrorPolicy::Disable);
+        write(&mut consumer, "1");
+        write(&mut consumer, "2");
+        assert_eq!(flaky.state(|s| s.attempts), 1);
+        assert_eq!(reported.lock().unwrap().len(), 1);
+
+        consumer.reopen();
+        write(&mut consumer, "3");
+        assert_eq!(flaky.state(|s| s.lines.clone()), ["3"]);
+    }
+
+    #[test]
+    fn fallback_writes_and_reports() {
+        let (flaky, fallback) = (Flaky::failing(1), Flaky::default());
+        let (mut consumer, reported) = flaky_consumer(&flaky, ErrorPolicy::fallback(fallback.clone()).unwrap());
+        write(&mut consumer, "1");
+        write(&mut consumer, "2");
+        assert_eq!(fallback.state(|s| s.lines.clone()), ["1"]);
+        assert_eq!(flaky.state(|s| s.lines.clone()), ["2"]);
+        assert_eq!(*reported.lock().unwrap(), ["outputer flaky failed (1 errors): flaky"]);
+    }
+
+    #[test]
+    fn on_error_gets_the_output_error() {
+        let flaky = Flaky::failing(2);
+        let errors = Arc::new(Mutex::new(vec![]));
+        let e = errors.clone();
+        let mut consumer = BaseConsumer::new()
+            .chain(LevelFilter::Trace, flaky.clone())
+            .unwrap()
+            .policy(ErrorPolicy::Report)
+            .on_error(move |error| match error {
+                Error::Output { desc, errors, error } => e.lock().unwrap().push((desc, errors, error.to_string())),
+                error => panic!("{:?}", error),
+            });
+        write(&mut consumer, "1");
+        write(&mut consumer, "2");
+
+        // the errors off the writes, such as cleaning the old files
+        flaky.state(|s| s.error = Some(io::Error::other("clean")));
+        write(&mut consumer, "3");
+        assert_eq!(
+            *errors.lock().unwrap(),
+            [
+                ("flaky".to_owned(), 1, "flaky".to_owned()),
+                ("flaky".to_owned(), 2, "flaky".to_owned()),
+                ("flaky".to_owned(), 3, "clean".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignore_reports_nothing() {
+        let flaky = Flaky::failing(2);
+        let (mut consumer, reported) = flaky_consumer(&flaky, ErrorPolicy::Ignore);
+        write(&mut consumer, "1");
+        flaky.state(|s| s.error = Some(io::Error::other("clean")));
+        write(&mut consumer, "2");
+        write(&mut consumer, "3");
+
+        assert!(reported.lock().unwrap().is_empty());
+        assert_eq!(errors(&consumer), 3);
+        assert_eq!(flaky.state(|s| s.lines.clone()), ["3"]);
+    }
+}
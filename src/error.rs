@@ -6,6 +6,36 @@ pub enum Error {
     Io(io::Error),
     Log(SetLoggerError),
     Desc(Cow<'static, str>),
+    /// an outputer failed, with the count of its errors so far
+    Output {
+        desc: String,
+        errors: u64,
+        error: io::Error,
+    },
+    /// a bad pattern of `PatternFormater`, with the byte position
+    Pattern {
+        pattern: String,
+        pos: usize,
+        desc: &'static str,
+    },
+}
+
+impl Error {
+    pub fn output<S: Into<String>>(desc: S, errors: u64, error: io::Error) -> Self {
+        Error::Output {
+            desc: desc.into(),
+            errors,
+            error,
+        }
+    }
+
+    pub fn pattern<S: Into<String>>(pattern: S, pos: usize, desc: &'static str) -> Self {
+        Error::Pattern {
+            pattern: pattern.into(),
+            pos,
+            desc,
+        }
+    }
 }
 
 impl fmt::Display for Error {
@@ -14,6 +44,8 @@ impl fmt::Display for Error {
             Error::Io(io) => write!(fmt, "{}", io),
             Error::Log(log) => write!(fmt, "{}", log),
             Error::Desc(desc) => write!(fmt, "{}", desc.as_ref()),
+            Error::Output { desc, errors, error } => write!(fmt, "outputer {} failed ({} errors): {}", desc, errors, error),
+            Error::Pattern { pattern, pos, desc } => write!(fmt, "{} at {} of pattern {:?}", desc, pos, pattern),
         }
     }
 }
@@ -24,6 +56,17 @@ impl std::error::Error for Error {
             Error::Io(io) => io.source(),
             Error::Log(_) => None,
             Error::Desc(_) => None,
+            Error::Output { error, .. } => Some(error),
+            Error::Pattern { .. } => None,
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::other(e.to_string()),
         }
     }
 }
@@ -50,17 +50,13 @@ impl BaseFilter {
     }
 
     fn check(&self, target: &str, level: Level) -> bool {
-        if let Some(idx) = self
-            .filters
-            .binary_search_by(|(t, _level)| {
-                if self.starts_with && target.starts_with(t) {
-                    Ordering::Equal
-                } else {
-                    t.as_str().cmp(target)
-                }
-            })
-            .ok()
-        {
+        if let Ok(idx) = self.filters.binary_search_by(|(t, _level)| {
+            if self.starts_with && target.starts_with(t) {
+                Ordering::Equal
+            } else {
+                t.as_str().cmp(target)
+            }
+        }) {
             unsafe { self.filters.get_unchecked(idx).1 >= level }
         } else {
             self.notfound
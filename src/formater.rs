@@ -1,11 +1,22 @@
+use crate::record::OwnedRecord;
+use chrono::format::{Fixed, Item, Numeric, Pad, StrftimeItems};
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
 use log::{Level, Record};
 
+use std::cell::RefCell;
 use std::convert::AsRef;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::{fmt, mem, thread};
 
 pub trait Formater: Send + Sync + 'static {
     fn boxed(self) -> Box<dyn Formater>;
     fn format(&self, record: &Record) -> String;
+    /// append the formatted record to the buffer, which may be recycled, the default pushes `format()`
+    fn format_into(&self, record: &Record, buf: &mut String) {
+        buf.push_str(&self.format(record))
+    }
 }
 
 impl Formater for BaseFormater {
@@ -16,16 +27,26 @@ impl Formater for BaseFormater {
     fn format(&self, record: &Record) -> String {
         self.formater_get()(self, record)
     }
+
+    fn format_into(&self, record: &Record, buf: &mut String) {
+        if self.custom {
+            buf.push_str(&self.formater_get()(self, record))
+        } else {
+            format_into(self, record, buf)
+        }
+    }
 }
 
 pub fn format(base: &BaseFormater, record: &Record) -> String {
-    let datetime = if base.local_get() {
-        chrono::Local::now().format(base.datetime_get())
-    } else {
-        chrono::Utc::now().format(base.datetime_get())
-    };
+    let mut buf = String::with_capacity(128);
+    format_into(base, record, &mut buf);
+    buf
+}
+
+pub fn format_into(base: &BaseFormater, record: &Record, buf: &mut String) {
+    base.datetime_cache.render(current_time(), base.local_get(), buf);
 
-    #[cfg(any(feature = "color"))]
+    #[cfg(feature = "color")]
     let level = FixedLevel::with_color(record.level(), base.color_get())
         .length(base.level_get())
         .into_colored()
@@ -34,22 +55,28 @@ pub fn format(base: &BaseFormater, record: &Record) -> String {
     let level = FixedLevel::new(record.level()).length(base.level_get());
 
     current_thread_name(|ctn| {
-        format!(
-            "{} {} [{}] ({}:{}) [{}] -- {}\n",
-            datetime,
+        write!(
+            buf,
+            " {} [{}] ({}:{}) [{}] -- {}",
             level,
             ctn,
             record.file().unwrap_or("*"),
             record.line().unwrap_or(0),
             record.target(),
-            record.args()
+            record.args(),
         )
-    })
+        .unwrap()
+    });
+
+    #[cfg(feature = "kv")]
+    crate::kv::push_pairs(buf, record.key_values());
+
+    buf.push('\n');
 }
 
 impl fmt::Debug for BaseFormater {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        #[cfg(any(feature = "color"))]
+        #[cfg(feature = "color")]
         return fmt
             .debug_struct("BaseFormater")
             .field("local", &self.local)
@@ -67,13 +94,18 @@ impl fmt::Debug for BaseFormater {
     }
 }
 
+type FormatFn = dyn Fn(&BaseFormater, &Record) -> String + Send + Sync + 'static;
+
 // #[derive(Debug)]
 pub struct BaseFormater {
     local: bool,
     level: usize,
     datetime: String,
-    formater: Box<dyn Fn(&Self, &Record) -> String + Send + Sync + 'static>,
-    #[cfg(any(feature = "color"))]
+    datetime_cache: DatetimeCache,
+    // the formater is not `format`
+    custom: bool,
+    formater: Box<FormatFn>,
+    #[cfg(feature = "color")]
     color: ColoredLogConfig,
 }
 
@@ -90,7 +122,9 @@ impl BaseFormater {
             level: 5,
             formater: Box::new(format) as _,
             datetime: "%Y-%m-%d %H:%M:%S.%3f".to_owned(),
-            #[cfg(any(feature = "color"))]
+            datetime_cache: DatetimeCache::new("%Y-%m-%d %H:%M:%S.%3f"),
+            custom: false,
+            #[cfg(feature = "color")]
             color: ColoredLogConfig::new(),
         }
     }
@@ -117,6 +151,7 @@ impl BaseFormater {
 
     pub fn datetime<S: Into<String>>(mut self, datetime: S) -> Self {
         self.datetime = datetime.into();
+        self.datetime_cache = DatetimeCache::new(&self.datetime);
         self
     }
 
@@ -130,6 +165,7 @@ impl BaseFormater {
         F: Fn(&Self, &Record) -> String + Send + Sync + 'static,
     {
         self.formater = Box::new(formater) as _;
+        self.custom = true;
         self
     }
 
@@ -138,47 +174,276 @@ impl BaseFormater {
         &*self.formater
     }
 
-    #[cfg(any(feature = "color"))]
+    #[cfg(feature = "color")]
     pub fn color(mut self, color_: bool) -> Self {
         self.color.color = color_;
         self
     }
 
     #[inline]
-    #[cfg(any(feature = "color"))]
+    #[cfg(feature = "color")]
     pub fn color_get(&self) -> &ColoredLogConfig {
         &self.color
     }
 
-    #[cfg(any(feature = "color"))]
+    #[cfg(feature = "color")]
     pub fn colored(mut self, color: ColoredLogConfig) -> Self {
         self.color = color;
         self
     }
 }
 
+/// Render the datetime, the parts other than the sub-second are cached per second and thread
+struct DatetimeCache {
+    datetime: String,
+    id: usize,
+    // the items before and after the sub-second item, None if the pattern can't be split
+    split: Option<(Vec<Item<'static>>, Option<Subsec>, Vec<Item<'static>>)>,
+}
+
+// the sub-second item, rendered by hand from the nanoseconds
+#[derive(Debug, Clone, Copy)]
+enum Subsec {
+    // `%f`
+    Numeric(Pad),
+    // `%.f`, 3, 6 or 9 digits after the dot, empty if 0
+    Auto,
+    // `%.3f` or `%3f`, the digits and whether after the dot
+    Fixed(usize, bool),
+}
+
+impl Subsec {
+    fn new(item: &Item) -> Self {
+        match item {
+            Item::Numeric(_, pad) => Subsec::Numeric(*pad),
+            Item::Fixed(Fixed::Nanosecond) => Subsec::Auto,
+            // the internal items can't be matched, render one to see
+            item => {
+                let probe = NaiveDate::from_ymd_opt(2020, 2, 29)
+                    .and_then(|d| d.and_hms_nano_opt(0, 0, 0, 123_456_789))
+                    .unwrap();
+                let rendered = probe.format_with_items(std::iter::once(item)).to_string();
+                let dot = rendered.starts_with('.');
+                Subsec::Fixed(rendered.len() - dot as usize, dot)
+            }
+        }
+    }
+
+    fn render(self, nanos: u32, buf: &mut String) {
+        let nanos = nanos % 1_000_000_000;
+        let (digits, dot) = match self {
+            Subsec::Numeric(Pad::Zero) => return write!(buf, "{:09}", nanos).unwrap(),
+            Subsec::Numeric(Pad::Space) => return write!(buf, "{:9}", nanos).unwrap(),
+            Subsec::Numeric(Pad::None) => return write!(buf, "{}", nanos).unwrap(),
+            Subsec::Auto if nanos == 0 => return,
+            Subsec::Auto if nanos.is_multiple_of(1_000_000) => (3, true),
+            Subsec::Auto if nanos.is_multiple_of(1_000) => (6, true),
+            Subsec::Auto => (9, true),
+            Subsec::Fixed(digits, dot) => (digits, dot),
+        };
+
+        if dot {
+            buf.push('.');
+        }
+        write!(buf, "{:01$}", nanos / 10u32.pow(9 - digits as u32), digits).unwrap()
+    }
+}
+
+struct CachedDatetime {
+    id: usize,
+    local: bool,
+    // the seconds, and whether in a leap second
+    second: (i64, bool),
+    prefix: String,
+    suffix: String,
+}
+
+thread_local!(static DATETIMES: RefCell<Vec<CachedDatetime>> = const { RefCell::new(Vec::new()) });
+
+impl DatetimeCache {
+    // the count of the formaters cached per thread
+    const CACHED: usize = 4;
+
+    fn new(datetime: &str) -> Self {
+        static ID: AtomicUsize = AtomicUsize::new(0);
+
+        let mut prefix = Vec::new();
+        let mut subsec = None;
+        let mut suffix = Vec::new();
+        let mut splitable = true;
+        for item in StrftimeItems::new(datetime) {
+            match item {
+                Item::Error | Item::Fixed(Fixed::RFC3339) => splitable = false,
+                Item::Numeric(Numeric::Nanosecond, _)
+                | Item::Fixed(Fixed::Nanosecond)
+                | Item::Fixed(Fixed::Nanosecond3)
+                | Item::Fixed(Fixed::Nanosecond6)
+                | Item::Fixed(Fixed::Nanosecond9)
+                | Item::Fixed(Fixed::Internal(_)) => {
+                    splitable &= subsec.is_none();
+                    subsec = Some(Subsec::new(&item));
+                }
+                item if subsec.is_none() => prefix.push(item.to_owned()),
+                item => suffix.push(item.to_owned()),
+            }
+        }
+
+        Self {
+            datetime: datetime.to_owned(),
+            id: ID.fetch_add(1, Ordering::Relaxed),
+            split: if splitable { Some((prefix, subsec, suffix)) } else { None },
+        }
+    }
+
+    fn render(&self, now: DateTime<Utc>, local: bool, buf: &mut String) {
+        let (prefix, subsec, suffix) = match self.split.as_ref() {
+            Some(split) => split,
+            None if local => return write!(buf, "{}", now.with_timezone(&chrono::Local).format(&self.datetime)).unwrap(),
+            None => return write!(buf, "{}", now.format(&self.datetime)).unwrap(),
+        };
+
+        let format = |buf: &mut String, items: &[Item<'static>]| {
+            if local {
+                write!(buf, "{}", now.with_timezone(&chrono::Local).format_with_items(items.iter()))
+            } else {
+                write!(buf, "{}", now.format_with_items(items.iter()))
+            }
+            .unwrap()
+        };
+
+        let second = (now.timestamp(), now.timestamp_subsec_nanos() >= 1_000_000_000);
+        DATETIMES.with(|cached| {
+            let mut cached = cached.borrow_mut();
+            let idx = match cached.iter().position(|c| c.id == self.id && c.local == local) {
+                Some(idx) => idx,
+                None => {
+                    if cached.len() >= Self::CACHED {
+                        cached.remove(0);
+                    }
+                    cached.push(CachedDatetime {
+                        id: self.id,
+                        local,
+                        second: (i64::MIN, false),
+                        prefix: String::new(),
+                        suffix: String::new(),
+                    });
+                    cached.len() - 1
+                }
+            };
+
+            let c = &mut cached[idx];
+            if c.second != second {
+                c.second = second;
+                c.prefix.clear();
+                format(&mut c.prefix, prefix);
+                c.suffix.clear();
+                format(&mut c.suffix, suffix);
+            }
+
+            buf.push_str(&c.prefix);
+            if let Some(subsec) = subsec {
+                subsec.render(now.timestamp_subsec_nanos(), buf);
+            }
+            buf.push_str(&c.suffix);
+        })
+    }
+}
+
+struct ThreadId(u64);
+
+thread_local!(static THREAD_NAME: Arc<str> = {
+    let thread = thread::current();
+    format!("{}.{}", unsafe { mem::transmute::<thread::ThreadId, ThreadId>(thread.id()).0 }, thread.name()
+    .map(|s| s.to_owned())
+    // unamed thread, main has 4 chars, aligned
+    .unwrap_or_else(||"****".to_owned())).into()
+});
+
+/// The name of the current thread, or the captured one for the deferred messages
 pub fn current_thread_name<F, U>(f: F) -> U
 where
-    F: Fn(&str) -> U,
+    F: FnOnce(&str) -> U,
 {
-    struct ThreadId(u64);
-
-    thread_local!(static THREAD_NAME: String = {
-        let thread = thread::current();
-        format!("{}.{}", unsafe { mem::transmute::<_, ThreadId>(thread.id()).0 }, thread.name()
-        .map(|s| s.to_owned())
-        // unamed thread, main has 4 chars, aligned
-        .unwrap_or_else(||"****".to_owned()))
-    });
+    match OwnedRecord::captured(|r| r.thread.clone()) {
+        Some(tname) => f(&tname),
+        None => THREAD_NAME.with(|tname| f(tname)),
+    }
+}
+
+pub(crate) fn current_thread() -> Arc<str> {
+    THREAD_NAME.with(|tname| tname.clone())
+}
+
+/// The time now, or the captured one for the deferred messages
+pub fn current_time() -> DateTime<Utc> {
+    OwnedRecord::captured(|r| r.time).unwrap_or_else(Utc::now)
+}
+
+/// The level in lowercase, such as "info"
+pub(crate) fn lowercase(level: Level) -> &'static str {
+    match level {
+        Level::Trace => "trace",
+        Level::Debug => "debug",
+        Level::Info => "info",
+        Level::Warn => "warn",
+        Level::Error => "error",
+    }
+}
+
+/// The time of `current_time()` by the chrono format, or RFC 3339 with milliseconds, for the structured formaters
+pub(crate) struct Timestamp<'a> {
+    now: DateTime<Utc>,
+    datetime: Option<&'a str>,
+    local: bool,
+}
+
+impl<'a> Timestamp<'a> {
+    pub fn new(datetime: Option<&'a str>, local: bool) -> Self {
+        Self {
+            now: current_time(),
+            datetime,
+            local,
+        }
+    }
+}
 
-    THREAD_NAME.with(|tname| f(&tname))
+impl fmt::Display for Timestamp<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let now = self.now;
+        match (self.datetime, self.local) {
+            (Some(datetime), true) => write!(f, "{}", now.with_timezone(&chrono::Local).format(datetime)),
+            (Some(datetime), false) => write!(f, "{}", now.format(datetime)),
+            (None, true) => f.write_str(&now.with_timezone(&chrono::Local).to_rfc3339_opts(SecondsFormat::Millis, true)),
+            (None, false) => f.write_str(&now.to_rfc3339_opts(SecondsFormat::Millis, true)),
+        }
+    }
+}
+
+/// Escapes `"`, `\` and the control characters written to the string, for the quoted values of JSON and logfmt
+pub(crate) struct Escape<'a>(pub &'a mut String);
+
+impl fmt::Write for Escape<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c {
+                '"' => self.0.push_str("\\\""),
+                '\\' => self.0.push_str("\\\\"),
+                '\n' => self.0.push_str("\\n"),
+                '\r' => self.0.push_str("\\r"),
+                '\t' => self.0.push_str("\\t"),
+                c if c < ' ' => write!(self.0, "\\u{:04x}", c as u32)?,
+                c => self.0.push(c),
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
 pub struct FixedLevel {
     str: &'static str,
     length: usize,
-    #[cfg(any(feature = "color"))]
+    #[cfg(feature = "color")]
     color: Option<Color>,
 }
 
@@ -196,7 +461,7 @@ impl FixedLevel {
         Self {
             str,
             length: 5,
-            #[cfg(any(feature = "color"))]
+            #[cfg(feature = "color")]
             color: None,
         }
     }
@@ -209,7 +474,7 @@ impl FixedLevel {
     }
 
     #[inline]
-    #[cfg(any(feature = "color"))]
+    #[cfg(feature = "color")]
     pub fn with_color(level: Level, color_: &ColoredLogConfig) -> Self {
         let (str, color) = match level {
             Level::Trace => ("TRACE", color_.trace),
@@ -226,7 +491,7 @@ impl FixedLevel {
         }
     }
 
-    #[cfg(any(feature = "color"))]
+    #[cfg(feature = "color")]
     pub fn into_colored(self) -> ColoredFixedLevel {
         ColoredFixedLevel::new(self)
     }
@@ -246,13 +511,95 @@ impl AsRef<str> for FixedLevel {
     }
 }
 
-#[cfg(any(feature = "color"))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    // counts the allocations of the current thread
+    struct Counting;
+
+    thread_local!(static ALLOCATED: Cell<usize> = const { Cell::new(0) });
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATED.try_with(|a| a.set(a.get() + 1)).ok();
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static COUNTING: Counting = Counting;
+
+    #[test]
+    fn datetime_cache_is_identical() {
+        let patterns = [
+            "%Y-%m-%d %H:%M:%S.%3f",
+            "%Y-%m-%dT%H:%M:%S%.f%:z",
+            "%H:%M:%S%.9f %Z|%s",
+            "%+",
+            "%f %a %b %e",
+            "%3f?",
+            "%6f %9f",
+            "%.6f %.9f",
+            "%-f|",
+            "%_f|",
+            "%.3f%.6f",
+        ];
+
+        let start = Utc.with_ymd_and_hms(2020, 2, 29, 23, 59, 58).unwrap();
+        for pattern in patterns.iter() {
+            let cache = DatetimeCache::new(pattern);
+            for ms in [0, 1, 999, 1000, 1001, 1500, 2000, 2001].iter() {
+                let now = start + Duration::milliseconds(*ms) + Duration::nanoseconds(7);
+                let render = |local| {
+                    let mut buf = String::new();
+                    cache.render(now, local, &mut buf);
+                    buf
+                };
+                assert_eq!(render(false), now.format(pattern).to_string(), "{}", pattern);
+                assert_eq!(
+                    render(true),
+                    now.with_timezone(&chrono::Local).format(pattern).to_string(),
+                    "{}",
+                    pattern
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn datetime_cache_never_allocates() {
+        let cache = DatetimeCache::new("%Y-%m-%d %H:%M:%S%.3f %:z");
+        let mut buf = String::with_capacity(64);
+        let second = Utc.with_ymd_and_hms(2020, 2, 29, 23, 59, 58).unwrap();
+
+        for local in [false, true] {
+            // cached once per second
+            cache.render(second, local, &mut buf);
+            buf.clear();
+
+            let allocated = ALLOCATED.with(Cell::get);
+            cache.render(second + Duration::milliseconds(500), local, &mut buf);
+            assert_eq!(ALLOCATED.with(Cell::get) - allocated, 0, "{}", buf);
+            buf.clear();
+        }
+    }
+}
+
+#[cfg(feature = "color")]
 use self::color::{Color, ColoredFixedLevel, ColoredLogConfig};
-#[cfg(any(feature = "color"))]
+#[cfg(feature = "color")]
 pub mod color {
     use super::FixedLevel;
     use log::Level;
-    use std::{fmt, mem};
+    use std::fmt;
     pub use yansi::Color;
 
     pub struct ColoredFgWith<T> {
@@ -380,7 +727,7 @@ pub mod color {
 
         #[inline]
         pub fn into_coloredfg(mut self) -> ColoredFgWith<Self> {
-            let color = mem::replace(&mut self.0.color, None);
+            let color = self.0.color.take();
             ColoredFgWith { color, text: self }
         }
     }
@@ -0,0 +1,305 @@
+use crate::formater::{current_thread_name, lowercase, Escape, Formater, Timestamp};
+use log::{Level, Record};
+use std::fmt::{self, Write};
+
+impl Formater for JsonFormater {
+    fn boxed(self) -> Box<dyn Formater> {
+        Box::new(self) as _
+    }
+
+    fn format(&self, record: &Record) -> String {
+        let mut json = String::with_capacity(256);
+        self.format_into(record, &mut json);
+        json
+    }
+
+    fn format_into(&self, record: &Record, json: &mut String) {
+        json.push('{');
+
+        for (key, value) in self.constants.iter() {
+            push_key(json, key);
+            json.push_str(value);
+        }
+
+        for (field, key) in self.fields.iter() {
+            let key = match key {
+                Some(key) => key,
+                None => continue,
+            };
+
+            match field {
+                JsonField::Time => {
+                    push_key(json, key);
+                    push_str(json, Timestamp::new(self.datetime.as_deref(), self.local));
+                }
+                JsonField::Level => {
+                    push_key(json, key);
+                    self.level.push(json, record.level());
+                }
+                JsonField::Target => {
+                    push_key(json, key);
+                    push_str(json, record.target());
+                }
+                JsonField::Module => {
+                    if let Some(module) = record.module_path() {
+                        push_key(json, key);
+                        push_str(json, module);
+                    }
+                }
+                JsonField::File => {
+                    if let Some(file) = record.file() {
+                        push_key(json, key);
+                        push_str(json, file);
+                    }
+                }
+                JsonField::Line => {
+                    if let Some(line) = record.line() {
+                        push_key(json, key);
+                        write!(json, "{}", line).unwrap();
+                    }
+                }
+                JsonField::Thread => {
+                    push_key(json, key);
+                    current_thread_name(|ctn| push_str(json, ctn));
+                }
+                JsonField::Message => {
+                    push_key(json, key);
+                    push_str(json, record.args());
+                }
+            }
+        }
+
+        #[cfg(feature = "kv")]
+        crate::kv::push_fields(json, record.key_values());
+
+        json.push_str("}\n");
+    }
+}
+
+/// The fields of `JsonFormater`, written in this order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JsonField {
+    Time,
+    Level,
+    Target,
+    Module,
+    File,
+    Line,
+    Thread,
+    Message,
+}
+
+/// How `JsonFormater` writes the level
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JsonLevel {
+    /// "INFO"
+    Upper,
+    /// "info"
+    Lower,
+    /// the numbers of Bunyan, 10 for trace to 50 for error
+    Number,
+}
+
+impl JsonLevel {
+    fn push(self, json: &mut String, level: Level) {
+        match self {
+            JsonLevel::Upper => push_str(json, level.as_str()),
+            JsonLevel::Lower => push_str(json, lowercase(level)),
+            JsonLevel::Number => {
+                let number = match level {
+                    Level::Trace => 10,
+                    Level::Debug => 20,
+                    Level::Info => 30,
+                    Level::Warn => 40,
+                    Level::Error => 50,
+                };
+                write!(json, "{}", number).unwrap();
+            }
+        }
+    }
+}
+
+/// One JSON object per line, the fields can be renamed or skipped
+#[derive(Debug, Clone)]
+pub struct JsonFormater {
+    local: bool,
+    datetime: Option<String>,
+    level: JsonLevel,
+    fields: Vec<(JsonField, Option<String>)>,
+    // the keys and the encoded values
+    constants: Vec<(String, String)>,
+}
+
+impl Default for JsonFormater {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JsonFormater {
+    pub fn new() -> Self {
+        Self {
+            local: false,
+            datetime: None,
+            level: JsonLevel::Upper,
+            fields: vec![
+                (JsonField::Time, Some("time".to_owned())),
+                (JsonField::Level, Some("level".to_owned())),
+                (JsonField::Target, Some("target".to_owned())),
+                (JsonField::Module, Some("module".to_owned())),
+                (JsonField::File, Some("file".to_owned())),
+                (JsonField::Line, Some("line".to_owned())),
+                (JsonField::Thread, Some("thread".to_owned())),
+                (JsonField::Message, Some("msg".to_owned())),
+            ],
+            constants: Vec::new(),
+        }
+    }
+
+    /// Bunyan-style: `v`, `name` of the application, `hostname`, `pid`, `time`, numeric `level` and `msg`
+    pub fn bunyan<S: fmt::Display>(name: S) -> Self {
+        let mut bunyan = Self::new().level(JsonLevel::Number).skip(JsonField::Module);
+        bunyan.constants.push(("v".to_owned(), "0".to_owned()));
+        bunyan = bunyan.constant("name", name).constant("hostname", hostname());
+        bunyan.constants.push(("pid".to_owned(), std::process::id().to_string()));
+        bunyan
+    }
+
+    /// ECS-style: `@timestamp`, `log.level`, `log.logger`, `log.origin.*`, `process.thread.name` and `message`
+    pub fn ecs() -> Self {
+        Self::new()
+            .level(JsonLevel::Lower)
+            .field(JsonField::Time, "@timestamp")
+            .field(JsonField::Level, "log.level")
+            .field(JsonField::Target, "log.logger")
+            .field(JsonField::Module, "log.origin.function")
+            .field(JsonField::File, "log.origin.file.name")
+            .field(JsonField::Line, "log.origin.file.line")
+            .field(JsonField::Thread, "process.thread.name")
+            .field(JsonField::Message, "message")
+    }
+
+    pub fn local(mut self, local: bool) -> Self {
+        self.local = local;
+        self
+    }
+
+    #[inline]
+    pub fn local_get(&self) -> bool {
+        self.local
+    }
+
+    /// the chrono format of the time, RFC 3339 with milliseconds default
+    pub fn datetime<S: Into<String>>(mut self, datetime: S) -> Self {
+        self.datetime = Some(datetime.into());
+        self
+    }
+
+    #[inline]
+    pub fn datetime_get(&self) -> Option<&str> {
+        self.datetime.as_deref()
+    }
+
+    pub fn level(mut self, level: JsonLevel) -> Self {
+        self.level = level;
+        self
+    }
+
+    #[inline]
+    pub fn level_get(&self) -> JsonLevel {
+        self.level
+    }
+
+    /// rename the field
+    pub fn field<S: Into<String>>(mut self, field: JsonField, key: S) -> Self {
+        if let Some(f) = self.fields.iter_mut().find(|f| f.0 == field) {
+            f.1 = Some(key.into());
+        }
+        self
+    }
+
+    /// do not write the field
+    pub fn skip(mut self, field: JsonField) -> Self {
+        if let Some(f) = self.fields.iter_mut().find(|f| f.0 == field) {
+            f.1 = None;
+        }
+        self
+    }
+
+    #[inline]
+    pub fn field_get(&self, field: JsonField) -> Option<&str> {
+        self.fields.iter().find(|f| f.0 == field).and_then(|f| f.1.as_deref())
+    }
+
+    /// write a constant string field before the others, such as the name of the service
+    pub fn constant<K: Into<String>, V: fmt::Display>(mut self, key: K, value: V) -> Self {
+        let mut encoded = String::new();
+        push_str(&mut encoded, value);
+        self.constants.push((key.into(), encoded));
+        self
+    }
+}
+
+// the environment variables of the shells and Windows, then the kernel's on Linux
+fn hostname() -> String {
+    ["HOSTNAME", "COMPUTERNAME"]
+        .iter()
+        .filter_map(|key| std::env::var(key).ok())
+        .chain(std::fs::read_to_string("/proc/sys/kernel/hostname"))
+        .chain(std::fs::read_to_string("/etc/hostname"))
+        .map(|host| host.trim().to_owned())
+        .find(|host| !host.is_empty())
+        .unwrap_or_else(|| "localhost".to_owned())
+}
+
+pub(crate) fn push_key(json: &mut String, key: &str) {
+    if !json.ends_with('{') {
+        json.push(',');
+    }
+    push_str(json, key);
+    json.push(':');
+}
+
+/// push the value as a JSON string, quoted and escaped
+pub(crate) fn push_str<T: fmt::Display>(json: &mut String, value: T) {
+    json.push('"');
+    write!(Escape(json), "{}", value).unwrap();
+    json.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OwnedRecord;
+
+    #[test]
+    fn json_formater_escapes() {
+        let record = Record::builder()
+            .args(format_args!("say \"hi\"\\\n\t\u{1}ünï"))
+            .level(Level::Warn)
+            .target("json")
+            .file_static(Some("src/json.rs"))
+            .line(Some(7))
+            .build();
+        let owned = OwnedRecord::capture(&record);
+
+        let json = owned.scope(|| JsonFormater::new().skip(JsonField::Time).format(&record));
+        assert_eq!(
+            json,
+            format!(
+                "{{\"level\":\"WARN\",\"target\":\"json\",\"file\":\"src/json.rs\",\"line\":7,\"thread\":\"{}\",\"msg\":\"say \\\"hi\\\"\\\\\\n\\t\\u0001ünï\"}}\n",
+                owned.thread
+            )
+        );
+
+        let json = owned.scope(|| JsonFormater::bunyan("app").format(&record));
+        let prefix = format!(
+            "{{\"v\":0,\"name\":\"app\",\"hostname\":\"{}\",\"pid\":{},\"time\":\"",
+            hostname(),
+            std::process::id()
+        );
+        assert!(json.starts_with(&prefix), "{}", json);
+        assert!(json.contains("Z\",\"level\":40,\"target\":\"json\","));
+        assert!(!hostname().is_empty());
+    }
+}
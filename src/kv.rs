@@ -0,0 +1,192 @@
+use crate::json;
+use crate::logfmt;
+use crate::spool::{self, Decoder};
+use log::kv::{Error, Key, Source, Value, VisitSource};
+use std::fmt::Write;
+
+/// The key-values of `log::Record` captured for the deferred messages
+#[derive(Debug, Clone, Default)]
+pub struct KeyValues(Vec<(String, OwnedValue)>);
+
+#[derive(Debug, Clone)]
+enum OwnedValue {
+    Bool(bool),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+    Str(String),
+}
+
+impl OwnedValue {
+    fn capture(value: &Value) -> Self {
+        value
+            .to_bool()
+            .map(OwnedValue::Bool)
+            .or_else(|| value.to_u64().map(OwnedValue::U64))
+            .or_else(|| value.to_i64().map(OwnedValue::I64))
+            .or_else(|| value.to_f64().map(OwnedValue::F64))
+            .unwrap_or_else(|| OwnedValue::Str(value.to_string()))
+    }
+
+    fn to_value(&self) -> Value<'_> {
+        match self {
+            OwnedValue::Bool(b) => Value::from(*b),
+            OwnedValue::U64(u) => Value::from(*u),
+            OwnedValue::I64(i) => Value::from(*i),
+            OwnedValue::F64(f) => Value::from(*f),
+            OwnedValue::Str(s) => Value::from(s.as_str()),
+        }
+    }
+}
+
+impl KeyValues {
+    pub fn capture(source: &dyn Source) -> Self {
+        let mut kvs = Vec::with_capacity(source.count());
+        visit(source, |k, v| kvs.push((k.as_str().to_owned(), OwnedValue::capture(&v))));
+        KeyValues(kvs)
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        spool::put_u32(buf, self.0.len() as u32);
+        for (k, v) in self.0.iter() {
+            spool::put_str(buf, k);
+            match v {
+                OwnedValue::Bool(b) => buf.extend_from_slice(&[0, *b as u8]),
+                OwnedValue::U64(u) => {
+                    buf.push(1);
+                    spool::put_u64(buf, *u);
+                }
+                OwnedValue::I64(i) => {
+                    buf.push(2);
+                    spool::put_u64(buf, *i as u64);
+                }
+                OwnedValue::F64(f) => {
+                    buf.push(3);
+                    spool::put_u64(buf, f.to_bits());
+                }
+                OwnedValue::Str(s) => {
+                    buf.push(4);
+                    spool::put_str(buf, s);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn decode(dec: &mut Decoder) -> Option<Self> {
+        let len = dec.u32()? as usize;
+        let mut kvs = Vec::with_capacity(len.min(64));
+        for _ in 0..len {
+            let k = dec.string()?;
+            let v = match dec.u8()? {
+                0 => OwnedValue::Bool(dec.u8()? != 0),
+                1 => OwnedValue::U64(dec.u64()?),
+                2 => OwnedValue::I64(dec.u64()? as i64),
+                3 => OwnedValue::F64(f64::from_bits(dec.u64()?)),
+                4 => OwnedValue::Str(dec.string()?),
+                _ => return None,
+            };
+            kvs.push((k, v));
+        }
+        Some(KeyValues(kvs))
+    }
+}
+
+impl Source for KeyValues {
+    fn visit<'kvs>(&'kvs self, visitor: &mut dyn VisitSource<'kvs>) -> Result<(), Error> {
+        for (k, v) in self.0.iter() {
+            visitor.visit_pair(Key::from_str(k), v.to_value())?;
+        }
+        Ok(())
+    }
+
+    fn count(&self) -> usize {
+        self.0.len()
+    }
+}
+
+struct Visitor<F>(F);
+
+impl<'kvs, F> VisitSource<'kvs> for Visitor<F>
+where
+    F: FnMut(Key<'kvs>, Value<'kvs>),
+{
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        (self.0)(key, value);
+        Ok(())
+    }
+}
+
+pub(crate) fn visit<'kvs, F>(source: &'kvs dyn Source, f: F)
+where
+    F: FnMut(Key<'kvs>, Value<'kvs>),
+{
+    source.visit(&mut Visitor(f)).ok();
+}
+
+/// push the key-values as ` k=v` pairs, the values are quoted as logfmt
+pub(crate) fn push_pairs(line: &mut String, source: &dyn Source) {
+    let mut value = String::new();
+    visit(source, |k, v| {
+        value.clear();
+        write!(value, "{}", v).unwrap();
+        logfmt::push_pair(line, true, k.as_str(), &value);
+    });
+}
+
+/// push the key-values as the fields of JSON object, the bools and numbers are not quoted
+pub(crate) fn push_fields(json: &mut String, source: &dyn Source) {
+    visit(source, |k, v| {
+        json::push_key(json, k.as_str());
+        match OwnedValue::capture(&v) {
+            OwnedValue::Bool(b) => write!(json, "{}", b).unwrap(),
+            OwnedValue::U64(u) => write!(json, "{}", u).unwrap(),
+            OwnedValue::I64(i) => write!(json, "{}", i).unwrap(),
+            OwnedValue::F64(f) if f.is_finite() => write!(json, "{}", f).unwrap(),
+            _ => json::push_str(json, v),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{BaseFormater, Formater, JsonField, JsonFormater, LogfmtField, LogfmtFormater, OwnedRecord};
+    use log::{Level, Record};
+
+    fn check(record: &Record) {
+        let owned = OwnedRecord::capture(record);
+
+        let formaters: [(Box<dyn Formater>, &str); 3] = [
+            (BaseFormater::new().boxed(), " -- login user_id=5 name=\"a b\" admin=true\n"),
+            (
+                JsonFormater::new().skip(JsonField::Time).skip(JsonField::Thread).boxed(),
+                ",\"msg\":\"login\",\"user_id\":5,\"name\":\"a b\",\"admin\":true}\n",
+            ),
+            (
+                LogfmtFormater::new().fields([LogfmtField::Message]).boxed(),
+                "msg=login user_id=5 name=\"a b\" admin=true\n",
+            ),
+        ];
+        for (formater, suffix) in formaters.iter() {
+            assert!(formater.format(record).ends_with(suffix), "{}", formater.format(record));
+            assert!(owned.with_record(|r| formater.format(r)).ends_with(suffix));
+        }
+    }
+
+    #[test]
+    fn formaters_render_key_values() {
+        let kvs: [(&str, log::kv::Value); 3] = [("user_id", 5u64.into()), ("name", "a b".into()), ("admin", true.into())];
+        check(
+            &Record::builder()
+                .args(format_args!("login"))
+                .level(Level::Info)
+                .target("kv")
+                .key_values(&kvs)
+                .build(),
+        );
+    }
+}
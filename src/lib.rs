@@ -2,7 +2,11 @@
 pub extern crate chrono;
 #[doc(hidden)]
 pub extern crate crossbeam_channel;
-#[cfg(any(feature = "color"))]
+#[cfg(feature = "gzip")]
+extern crate flate2;
+#[cfg(all(unix, feature = "signal"))]
+extern crate signal_hook;
+#[cfg(feature = "color")]
 extern crate yansi;
 // re-export log crate
 #[allow(unused_imports)]
@@ -16,76 +20,201 @@ mod consumer;
 mod error;
 mod filter;
 mod formater;
+mod json;
+#[cfg(feature = "kv")]
+mod kv;
+mod logfmt;
+mod pattern;
+mod pool;
+mod queue;
+mod receiver;
+mod record;
+mod rotate;
+mod spool;
+mod stats;
 
 // re-export macros
 pub use log::{debug, error, info, log, log_enabled, trace, warn};
 
-pub use consumer::{BaseConsumer, Consumer, Outputer};
+pub use consumer::{BaseConsumer, Consumer, ErrorPolicy, Outputer};
 pub use error::Error;
 pub use filter::{BaseFilter, Filter};
-#[cfg(any(feature = "color"))]
+#[cfg(feature = "color")]
 pub use formater::color::{ColoredFg, ColoredFgWith, ColoredFixedLevel, ColoredLogConfig};
-pub use formater::{current_thread_name, BaseFormater, FixedLevel, Formater};
+pub use formater::{current_thread_name, current_time, BaseFormater, FixedLevel, Formater};
+pub use json::{JsonField, JsonFormater, JsonLevel};
+#[cfg(feature = "kv")]
+pub use kv::KeyValues;
+pub use logfmt::{LogfmtField, LogfmtFormater};
+pub use pattern::PatternFormater;
+pub use queue::Transport;
+pub use receiver::Receiver;
+pub use record::OwnedRecord;
+pub use rotate::{LogFile, Retention, RotatingFile, Rotation, TimeRotatingFile};
+pub use stats::{OutputerStats, Stats};
+
+use pool::Pool;
+use queue::{Dequeue, Queue};
+use spool::Spool;
+use stats::Counters;
 
 use crossbeam_channel as channel;
 
 use log::{set_logger, set_max_level, Level, Log, Metadata, Record, SetLoggerError};
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::io::{stderr, stdout};
+use std::path::{Path, PathBuf};
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    Arc, Mutex,
 };
-use std::{fmt, mem, thread};
+use std::time::{Duration, Instant};
+use std::{fmt, ptr, thread};
 
 static mut LOGGER: Option<NonblockLoggerGlobal> = None;
 
 const NAME: &str = "log";
+const BUFFERS: usize = 256;
 
 pub struct NonblockLogger {
     name: Option<String>,
     filter: Box<dyn Filter>,
     formater: Box<dyn Formater>,
     consumer: Option<Box<dyn Consumer>>,
-    sendfn: Box<dyn Fn(&NonblockLogger, Option<Message>) + Send + Sync + 'static>,
-    sender: Sender,
-    receiver: Option<Receiver>,
+    sendfn: Box<SendFn>,
+    sender: Queue,
+    // only taken by `start`, the lock keeps the logger `Sync`
+    receiver: Mutex<Option<Dequeue>>,
+    capacity: Option<usize>,
+    transport: Transport,
     exited: AtomicBool,
     quiet: bool,
+    deferred: bool,
+    structured: bool,
+    backpressure: Backpressure,
+    // the level and channel of the priority lane
+    priority: Option<(Level, channel::Sender<Event>)>,
+    priority_receiver: Option<channel::Receiver<Event>>,
+    // to drop the oldest messages, taken when the consumer thread exited to disconnect the channel
+    oldest: Mutex<Option<channel::Receiver<Event>>>,
+    // dropped since the last report
+    dropped: AtomicU64,
+    // the budget and bytes of the queued messages
+    max_bytes: Option<usize>,
+    queued_bytes: AtomicUsize,
+    // the overflowed messages, replayed by `Receiver`
+    spool: Option<Spool>,
+    counters: Counters,
+    // the emptied buffers of messages
+    pool: Pool,
+    #[cfg(all(unix, feature = "signal"))]
+    sighup: bool,
 }
 
-pub type Sender = channel::Sender<Option<Message>>;
-pub type Receiver = channel::Receiver<Option<Message>>;
+type SendFn = dyn Fn(&NonblockLogger, Event) + Send + Sync + 'static;
+
+/// The items sent to the consumer thread, in order
+#[derive(Debug, Clone)]
+pub enum Event {
+    Message(Message),
+    /// reopen the files of the outputers, such as after logrotate moved them
+    Reopen,
+    /// flush the outputers, then reply to the waiting thread
+    Flush(channel::Sender<()>),
+    Exit,
+}
+
+/// What the default `sendfn` does if the bounded channel is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+    /// panic, or drop the message if `quiet()`, the default
+    Panic,
+    /// block until the consumer thread catches up, drop the message if timeout
+    Block(Option<Duration>),
+    DropNewest,
+    /// drop the oldest messages in the channel, or the newest with `Transport::PerThread`
+    DropOldest,
+    /// drop the messages below the level, block for the others, such as `DropBelow(Level::Warn)`
+    DropBelow(Level),
+}
 
 #[derive(Debug, Clone)]
 pub struct Message {
+    /// the formatted record, empty for the deferred message until `Receiver` formats it
     pub content: String,
     pub level: Level,
+    /// the metadata of the record, for `structured()` or `deferred()`
+    pub record: Option<Box<OwnedRecord>>,
 }
 
 impl Message {
     pub fn new(content: String, level: Level) -> Self {
-        Self { content, level }
+        Self {
+            content,
+            level,
+            record: None,
+        }
+    }
+
+    pub fn structured(content: String, record: OwnedRecord) -> Self {
+        Self {
+            content,
+            level: record.level,
+            record: Some(Box::new(record)),
+        }
+    }
+
+    pub fn deferred(record: OwnedRecord) -> Self {
+        Self::structured(String::new(), record)
+    }
+
+    /// the bytes counted by `NonblockLogger::max_bytes`
+    pub fn bytes(&self) -> usize {
+        self.content.len() + self.record.as_ref().map(|r| r.args.len()).unwrap_or(0)
+    }
+}
+
+impl Default for NonblockLogger {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl NonblockLogger {
     pub fn new() -> Self {
-        let (mp, mc) = channel::unbounded();
-        Self::new2(mp, mc)
+        Self::new2(None)
     }
 
     pub fn with_capacity(cap: usize) -> Self {
-        let (mp, mc) = channel::bounded(cap);
-        Self::new2(mp, mc)
+        Self::new2(Some(cap))
     }
 
-    fn new2(mp: Sender, mc: Receiver) -> Self {
+    fn new2(capacity: Option<usize>) -> Self {
+        let (mp, mc) = queue::queue(Transport::Channel, capacity);
         Self {
             name: None,
             sender: mp,
-            receiver: Some(mc),
+            receiver: Mutex::new(Some(mc)),
+            capacity,
+            transport: Transport::Channel,
             sendfn: Box::new(sendfn) as _,
             exited: AtomicBool::new(false),
             quiet: false,
+            deferred: false,
+            structured: false,
+            backpressure: Backpressure::Panic,
+            priority: None,
+            priority_receiver: None,
+            oldest: Mutex::new(None),
+            dropped: AtomicU64::new(0),
+            max_bytes: None,
+            queued_bytes: AtomicUsize::new(0),
+            spool: None,
+            counters: Counters::default(),
+            pool: Pool::new(BUFFERS),
+            #[cfg(all(unix, feature = "signal"))]
+            sighup: false,
             filter: BaseFilter::new().boxed().unwrap(),
             formater: BaseFormater::new().boxed(),
             consumer: Some(BaseConsumer::new().boxed().unwrap()),
@@ -94,7 +223,7 @@ impl NonblockLogger {
 
     pub fn sendfn<F>(mut self, sendfn: F) -> Self
     where
-        F: Fn(&NonblockLogger, Option<Message>) + Send + Sync + 'static,
+        F: Fn(&NonblockLogger, Event) + Send + Sync + 'static,
     {
         self.sendfn = Box::new(sendfn) as _;
         self
@@ -111,6 +240,7 @@ impl NonblockLogger {
     }
 
     pub fn consumer<C: Consumer>(mut self, consumer: C) -> Result<Self, Error> {
+        self.structured |= consumer.structured();
         self.consumer = Some(consumer.boxed()?);
         Ok(self)
     }
@@ -134,33 +264,167 @@ impl NonblockLogger {
         self.quiet
     }
 
-    fn log_to_channel(mut self) -> Result<Receiver, SetLoggerError> {
-        let mc = mem::replace(&mut self.receiver, None).expect("NonblockLogger's receiver is None!");
+    /// Capture the records on the caller's thread, and format them on the consumer thread
+    pub fn deferred(mut self, deferred: bool) -> Self {
+        self.deferred = deferred;
+        self
+    }
+
+    pub fn deferred_get(&self) -> bool {
+        self.deferred
+    }
+
+    /// Send the metadata of the records with the formatted content, for the consumers to route or re-render
+    pub fn structured(mut self, structured: bool) -> Self {
+        self.structured = structured;
+        self
+    }
+
+    pub fn structured_get(&self) -> bool {
+        self.structured
+    }
+
+    /// Set the policy for the bounded channel is full, a "N messages dropped" line is sent after the pressure clears
+    pub fn backpressure(mut self, backpressure: Backpressure) -> Self {
+        self.backpressure = backpressure;
+        self
+    }
+
+    pub fn backpressure_get(&self) -> Backpressure {
+        self.backpressure
+    }
+
+    /// Append the messages to the spool file instead of blocking or dropping them if the channel is full,
+    /// the consumer thread replays them in order once it catches up.
+    ///
+    /// The messages left in the file by a previous run are replayed first, the backpressure applies only if failed to write it
+    pub fn spool<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.spool = Some(Spool::new(path.into()));
+        self
+    }
+
+    pub fn spool_get(&self) -> Option<&Path> {
+        self.spool.as_ref().map(|s| s.path())
+    }
+
+    /// Limit the bytes of the queued messages, the backpressure applies once exceeded,
+    /// a message larger than it is only sent to the empty channel
+    pub fn max_bytes(mut self, bytes: usize) -> Self {
+        self.max_bytes = Some(bytes);
+        self
+    }
+
+    pub fn max_bytes_get(&self) -> Option<usize> {
+        self.max_bytes
+    }
+
+    /// Send the messages at or above the level through a separate channel of this capacity, the consumer drains it first,
+    /// the messages go to the normal channel if it is full, 0 capacity disables the lane
+    pub fn priority(mut self, level: Level, capacity: usize) -> Self {
+        if capacity == 0 {
+            self.priority = None;
+            self.priority_receiver = None;
+            return self;
+        }
+
+        let (mp, mc) = channel::bounded(capacity);
+        self.priority = Some((level, mp));
+        self.priority_receiver = Some(mc);
+        self
+    }
+
+    pub fn priority_get(&self) -> Option<Level> {
+        self.priority.as_ref().map(|p| p.0)
+    }
+
+    /// Set how the messages are sent to the consumer thread, `Transport::PerThread` for many logging threads
+    pub fn transport(mut self, transport: Transport) -> Self {
+        let (mp, mc) = queue::queue(transport, self.capacity);
+        self.sender = mp;
+        self.receiver = Mutex::new(Some(mc));
+        self.transport = transport;
+        self
+    }
+
+    pub fn transport_get(&self) -> Transport {
+        self.transport
+    }
+
+    /// Keep at most this many emptied buffers for the messages, 256 default, 0 to disable the recycle
+    pub fn recycle(mut self, buffers: usize) -> Self {
+        self.pool = Pool::new(buffers);
+        self
+    }
+
+    /// Send `Event::Reopen` to the consumer thread when the process receives SIGHUP
+    #[cfg(all(unix, feature = "signal"))]
+    pub fn reopen_on_sighup(mut self) -> Self {
+        self.sighup = true;
+        self
+    }
+
+    fn log_to_channel(self) -> Result<&'static Self, SetLoggerError> {
         set_max_level(self.filter.maxlevel());
         let nob = NonblockLoggerGlobal(Arc::new(self));
 
         unsafe {
             LOGGER = Some(nob);
-            assert!(LOGGER.is_some());
-            let np = LOGGER.as_ref().unwrap() as _;
+            let np = (*ptr::addr_of!(LOGGER)).as_ref().unwrap() as _;
             set_logger(np)?;
         }
 
-        Ok(mc)
+        Ok(Self::global().unwrap())
+    }
+
+    pub fn spawn(self) -> Result<JoinHandle, Error> {
+        // listen before the logger is installed, not to fail after that
+        #[cfg(all(unix, feature = "signal"))]
+        let sighup = if self.sighup { Some(Self::listen_sighup()?) } else { None };
+
+        self.start(|logger| {
+            let global = logger.log_to_channel()?;
+            #[cfg(all(unix, feature = "signal"))]
+            if let Some(sighup) = sighup {
+                sighup.send(global).ok();
+            }
+            Ok(global)
+        })
     }
 
-    pub fn spawn(mut self) -> Result<JoinHandle, Error> {
-        let name = mem::replace(&mut self.name, None).unwrap_or_else(|| NAME.into());
-        let mut consumer = mem::replace(&mut self.consumer, None).unwrap();
-        let mc = self.log_to_channel()?;
+    // run the consumer on its own thread, after `install` made the logger static
+    fn start<F>(mut self, install: F) -> Result<JoinHandle, Error>
+    where
+        F: FnOnce(Self) -> Result<&'static Self, Error>,
+    {
+        let name = self.name.take().unwrap_or_else(|| NAME.into());
+        let mut consumer = self.consumer.take().unwrap();
+        let priority = self.priority_receiver.take();
+        let mc = self
+            .receiver
+            .get_mut()
+            .unwrap()
+            .take()
+            .expect("NonblockLogger's receiver is None!");
+        if self.backpressure == Backpressure::DropOldest {
+            self.oldest = Mutex::new(mc.channel());
+        }
+        // the messages left by a crashed run are replayed first, opened again by the first spilled message if failed
+        if let Some(spool) = self.spool.as_ref() {
+            spool.recover().ok();
+        }
+
+        let logger = install(self)?;
+        let mc = Receiver::new(mc, priority, logger);
 
         thread::Builder::new()
             .name(name)
             .spawn(move || {
+                // even if the consumer panicked, not to block the senders
+                let _exited = Exited(logger);
+                CONSUMER_THREAD.with(|c| c.set(true));
                 consumer.consume(mc);
-                Self::global().map(|g| g.exit());
             })
-            .map(|jh| JoinHandle::new(Self::global().unwrap(), jh))
+            .map(|jh| JoinHandle::new(logger, jh))
             .map_err(Error::from)
     }
 
@@ -177,15 +441,296 @@ impl NonblockLogger {
 
         self.spawn()
     }
+
+    /// log the messages at or below `Info` to stdout, and `Warn`, `Error` to stderr
+    pub fn log_split_stdout_stderr(mut self) -> Result<JoinHandle, Error> {
+        self.consumer = Some(split(stdout(), stderr())?.boxed()?);
+
+        self.spawn()
+    }
+}
+
+// the messages at or below `Info` to `out`, `Warn` and `Error` to `err`
+fn split<O: Outputer, E: Outputer>(out: O, err: E) -> Result<BaseConsumer, Error> {
+    BaseConsumer::new()
+        .chain_range(Level::Trace..=Level::Info, out)?
+        .chain_range(Level::Warn..=Level::Error, err)
 }
 
 impl NonblockLogger {
     pub fn global() -> Option<&'static Self> {
-        unsafe { LOGGER.as_ref().map(|g| g.0.as_ref()) }
+        unsafe { (*ptr::addr_of!(LOGGER)).as_ref().map(|g| g.0.as_ref()) }
     }
 
     pub fn send_exit(&self) {
-        (*self.sendfn)(self, None)
+        (*self.sendfn)(self, Event::Exit)
+    }
+
+    pub fn send_reopen(&self) {
+        (*self.sendfn)(self, Event::Reopen)
+    }
+
+    fn overflow(&self, event: Event) {
+        // the consumer thread would block itself
+        let block = !CONSUMER_THREAD.with(|c| c.get());
+        let level = match &event {
+            Event::Message(message) => message.level,
+            _ => {
+                if block {
+                    self.sender.send(event).ok();
+                }
+                return;
+            }
+        };
+
+        let sent = match self.backpressure {
+            // only the full rings of the unbounded `Transport::PerThread` get here with `Panic`, which block
+            Backpressure::Block(None) | Backpressure::Panic if block => self.send_until(event, None),
+            Backpressure::Block(Some(timeout)) if block => self.send_until(event, Some(Instant::now() + timeout)),
+            Backpressure::DropBelow(below) if block && level <= below => self.send_until(event, None),
+            Backpressure::DropOldest => self.drop_oldest(event),
+            _ => false,
+        };
+
+        if sent {
+            self.counters.queued(self.sender.len());
+        } else {
+            self.drop_message();
+        }
+    }
+
+    // reserve the bytes of the message, beyond the budget if force
+    fn reserve(&self, event: &Event, force: bool) -> bool {
+        let bytes = match event {
+            Event::Message(message) => message.bytes(),
+            _ => return true,
+        };
+
+        let queued = self.queued_bytes.fetch_add(bytes, Ordering::Relaxed);
+        match self.max_bytes {
+            Some(max) if !force && queued > 0 && queued + bytes > max => {
+                self.queued_bytes.fetch_sub(bytes, Ordering::Relaxed);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// release the bytes of the message received or dropped
+    pub(crate) fn release(&self, message: &Message) {
+        self.queued_bytes.fetch_sub(message.bytes(), Ordering::Relaxed);
+    }
+
+    fn try_send(&self, event: Event) -> Result<(), channel::TrySendError<Event>> {
+        use crossbeam_channel::TrySendError::*;
+
+        if !self.reserve(&event, false) {
+            return Err(Full(event));
+        }
+
+        self.try_send_reserved(event)
+    }
+
+    // send the message which bytes are reserved, released if failed
+    fn try_send_reserved(&self, event: Event) -> Result<(), channel::TrySendError<Event>> {
+        use crossbeam_channel::TrySendError::*;
+
+        self.sender.try_send(event).inspect_err(|e| {
+            if let Full(Event::Message(message)) | Disconnected(Event::Message(message)) = e {
+                self.release(message);
+            }
+        })
+    }
+
+    // wait for the budget and the channel
+    fn send_until(&self, event: Event, deadline: Option<Instant>) -> bool {
+        let mut spin = 0;
+        while !self.reserve(&event, false) {
+            if self.exited() || deadline.map(|d| Instant::now() >= d).unwrap_or(false) {
+                return false;
+            }
+
+            spin += 1;
+            if spin < 16 {
+                thread::yield_now();
+            } else {
+                thread::sleep(Duration::from_micros(100));
+            }
+        }
+
+        let unsent = match deadline {
+            Some(deadline) => self
+                .sender
+                .send_timeout(event, deadline.saturating_duration_since(Instant::now()))
+                .map_err(|e| e.into_inner()),
+            None => self.sender.send(event).map_err(|e| e.into_inner()),
+        };
+
+        match unsent {
+            Ok(()) => true,
+            Err(Event::Message(message)) => {
+                self.release(&message);
+                false
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn drop_oldest(&self, mut event: Event) -> bool {
+        use crossbeam_channel::TrySendError::*;
+
+        // the queues of `Transport::PerThread` are only popped by the consumer thread, drop the message instead
+        let oldest = match self.oldest.lock().unwrap().clone() {
+            Some(oldest) => oldest,
+            None => return false,
+        };
+        // the others taken, sent back into the freed slots before the message
+        let mut kept = VecDeque::new();
+        let requeue = |kept: &mut VecDeque<Event>| {
+            while let Some(other) = kept.pop_front() {
+                if let Err(e) = self.sender.try_send(other) {
+                    kept.push_front(e.into_inner());
+                    break;
+                }
+            }
+        };
+
+        let mut sent = false;
+        for _ in 0..=self.sender.capacity().unwrap_or_else(|| self.sender.len()) {
+            requeue(&mut kept);
+            if kept.is_empty() {
+                match self.try_send(event) {
+                    Ok(()) => {
+                        sent = true;
+                        break;
+                    }
+                    Err(Full(e)) => event = e,
+                    Err(Disconnected(_)) => return false,
+                }
+            }
+
+            match oldest.try_recv().ok() {
+                Some(Event::Message(message)) => {
+                    self.release(&message);
+                    self.drop_message()
+                }
+                Some(other) => kept.push_back(other),
+                None => {}
+            }
+        }
+
+        // only lost if the other threads took the freed slots all the time
+        requeue(&mut kept);
+        sent
+    }
+
+    // append the message to the spool if the channel is full, or the spool has messages not replayed to keep the order
+    fn spill(&self, event: Event, full: bool) -> Result<(), Event> {
+        match self.spool.as_ref() {
+            Some(spool) if full || spool.pending() => spool.push(event).map(|()| {
+                // the consumer thread may wait for the channel already
+                self.sender.wake();
+                self.counters.spooled()
+            }),
+            _ => Err(event),
+        }
+    }
+
+    fn send_dropped(&self) {
+        if self.dropped.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped == 0 {
+            return;
+        }
+
+        let message = self.message(
+            &Record::builder()
+                .args(format_args!("{} messages dropped", dropped))
+                .level(Level::Warn)
+                .target(module_path!())
+                .build(),
+        );
+
+        if self.try_send(Event::Message(message)).is_err() {
+            self.dropped.fetch_add(dropped, Ordering::Relaxed);
+        }
+    }
+
+    /// wait until all the messages sent before are written and the outputers are flushed,
+    /// returns false if the consumer thread exited
+    pub fn flush(&self) -> bool {
+        self.flush_until(None)
+    }
+
+    pub fn flush_timeout(&self, timeout: Duration) -> bool {
+        self.flush_until(Some(Instant::now() + timeout))
+    }
+
+    fn flush_until(&self, deadline: Option<Instant>) -> bool {
+        // the consumer thread can't wait for itself
+        if self.exited() || CONSUMER_THREAD.with(|c| c.get()) {
+            return false;
+        }
+
+        // wait in slices, the consumer may exit or panic with the flush queued
+        let waiting = || !self.exited() && deadline.map(|d| Instant::now() < d).unwrap_or(true);
+        let timeout = || {
+            let slice = Duration::from_millis(100);
+            deadline
+                .map(|d| d.saturating_duration_since(Instant::now()).min(slice))
+                .unwrap_or(slice)
+        };
+
+        let (mp, mc) = channel::bounded(1);
+        let mut event = Event::Flush(mp);
+        loop {
+            match self.sender.send_timeout(event, timeout()) {
+                Ok(()) => break,
+                Err(channel::SendTimeoutError::Timeout(e)) if waiting() => event = e,
+                Err(_) => return false,
+            }
+        }
+
+        loop {
+            match mc.recv_timeout(timeout()) {
+                Ok(()) => return true,
+                Err(channel::RecvTimeoutError::Timeout) if waiting() => continue,
+                Err(_) => return false,
+            }
+        }
+    }
+
+    // the listener gets the logger once it's installed, or exits if failed to install
+    #[cfg(all(unix, feature = "signal"))]
+    fn listen_sighup() -> Result<channel::Sender<&'static Self>, Error> {
+        use signal_hook::{consts::SIGHUP, iterator::Signals};
+
+        let mut signals = Signals::new([SIGHUP])?;
+        let (mp, mc) = channel::bounded::<&'static Self>(1);
+        thread::Builder::new().name("log-sighup".into()).spawn(move || {
+            let logger = match mc.recv() {
+                Ok(logger) => logger,
+                Err(_) => return,
+            };
+
+            for _ in signals.forever() {
+                // wait for the full channel, instead of `sendfn` which may drop it or panic
+                while !logger.exited() {
+                    match logger.sender.send_timeout(Event::Reopen, Duration::from_millis(100)) {
+                        Err(channel::SendTimeoutError::Timeout(_)) => continue,
+                        _ => break,
+                    }
+                }
+                if logger.exited() {
+                    break;
+                }
+            }
+        })?;
+
+        Ok(mp)
     }
 
     pub fn exit(&self) {
@@ -196,41 +741,153 @@ impl NonblockLogger {
         self.exited.load(Ordering::Relaxed)
     }
 
+    /// the messages in channel, including the priority lane and the spool
     pub fn messages_in_channel(&self) -> usize {
         self.sender.len()
+            + self.priority.as_ref().map(|p| p.1.len()).unwrap_or(0)
+            + self.spool.as_ref().map(|s| s.len()).unwrap_or(0)
+    }
+
+    /// the bytes of the messages in channel
+    pub fn bytes_in_channel(&self) -> usize {
+        self.queued_bytes.load(Ordering::Relaxed)
+    }
+
+    // returns the event back if the priority lane is not for it or full
+    fn send_priority(&self, event: Event) -> Result<(), Event> {
+        let lane = match (&event, self.priority.as_ref()) {
+            (Event::Message(message), Some((level, lane))) if message.level <= *level => lane,
+            _ => return Err(event),
+        };
+
+        // the priority lane is bounded by its capacity
+        self.reserve(&event, true);
+        if let Err(e) = lane.try_send(event) {
+            let event = e.into_inner();
+            if let Event::Message(message) = &event {
+                self.release(message);
+            }
+            return Err(event);
+        }
+
+        self.sender.wake();
+        Ok(())
+    }
+
+    /// A snapshot of the counters
+    pub fn stats(&self) -> Stats {
+        self.counters.snapshot()
+    }
+
+    pub(crate) fn formater_get(&self) -> &dyn Formater {
+        &*self.formater
+    }
+
+    pub(crate) fn pool(&self) -> &Pool {
+        &self.pool
+    }
+
+    pub(crate) fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    pub(crate) fn drop_message(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        self.counters.dropped(1);
     }
 }
 
 // if channel is full, send will block, but try_send don't
-fn sendfn(logger: &NonblockLogger, msg: Option<Message>) {
-    let res = logger.sender.try_send(msg);
+fn sendfn(logger: &NonblockLogger, event: Event) {
+    use crossbeam_channel::TrySendError::*;
 
-    if let Err(e) = &res {
-        if logger.quiet {
-            return;
-        }
+    // report the dropped messages before the current one
+    logger.send_dropped();
 
-        use crossbeam_channel::TrySendError::*;
-        let is_some = match e {
-            Full(t) => t,
-            Disconnected(t) => t,
-        }
-        .is_some();
+    let event = match logger.send_priority(event) {
+        Ok(()) => return,
+        Err(event) => event,
+    };
 
-        let e = if is_some {
-            "NonblockLogger send log message falied!"
-        } else {
-            "NonblockLogger send exit message falied!"
-        };
+    let event = match logger.spill(event, false) {
+        Ok(()) => return,
+        Err(event) => event,
+    };
+
+    // over the `max_bytes` budget, the backpressure applies as if the channel was full
+    let budget = logger.reserve(&event, false);
+    let sent = if budget {
+        logger.try_send_reserved(event)
+    } else {
+        Err(Full(event))
+    };
+
+    let e = match sent {
+        Ok(()) => return logger.counters.queued(logger.sender.len()),
+        Err(Full(event)) => match logger.spill(event, true) {
+            Ok(()) => return,
+            Err(event) if logger.backpressure != Backpressure::Panic => return logger.overflow(event),
+            // the full rings of the unbounded `Transport::PerThread` block, the messages over the budget don't
+            Err(event) if budget && logger.capacity.is_none() => return logger.overflow(event),
+            Err(event) => Full(event),
+        },
+        Err(e) => e,
+    };
+
+    if let Full(Event::Message(_)) = e {
+        logger.drop_message();
+    }
+
+    if logger.quiet {
+        return;
+    }
+
+    let event = match &e {
+        Full(t) => t,
+        Disconnected(t) => t,
+    };
 
-        res.expect(e);
+    let msg = match event {
+        Event::Message(_) => "NonblockLogger send log message falied!",
+        Event::Reopen => "NonblockLogger send reopen message falied!",
+        Event::Flush(_) => "NonblockLogger send flush message falied!",
+        Event::Exit => "NonblockLogger send exit message falied!",
+    };
+
+    panic!("{}: {:?}", msg, e);
+}
+
+// marks the logger exited when the consumer thread ends, and disconnects the channel
+struct Exited(&'static NonblockLogger);
+
+impl Drop for Exited {
+    fn drop(&mut self) {
+        self.0.exit();
+        self.0.oldest.lock().unwrap().take();
     }
 }
 
+thread_local!(static CONSUMER_THREAD: Cell<bool> = const { Cell::new(false) });
+
 pub fn messages_in_channel() -> usize {
     NonblockLogger::global().map(|g| g.messages_in_channel()).unwrap_or(0)
 }
 
+pub fn bytes_in_channel() -> usize {
+    NonblockLogger::global().map(|g| g.bytes_in_channel()).unwrap_or(0)
+}
+
+pub fn stats() -> Stats {
+    NonblockLogger::global().map(|g| g.stats()).unwrap_or_default()
+}
+
+/// Let the consumer thread reopen the files of its outputers, after all the messages sent before
+pub fn reopen() {
+    if let Some(g) = NonblockLogger::global() {
+        g.send_reopen()
+    }
+}
+
 pub struct JoinHandle {
     logger: &'static NonblockLogger,
     join_handle: Option<thread::JoinHandle<()>>,
@@ -244,9 +901,18 @@ impl JoinHandle {
         }
     }
 
+    /// wait until all the messages sent before are written and the outputers are flushed
+    pub fn flush(&self) -> bool {
+        self.logger.flush()
+    }
+
+    pub fn flush_timeout(&self, timeout: Duration) -> bool {
+        self.logger.flush_timeout(timeout)
+    }
+
     /// wait the log thread exit, can be called multiple times, but only takes effect for the first time.
     pub fn join(&mut self) {
-        mem::replace(&mut self.join_handle, None).map(|h| {
+        self.join_handle.take().map(|h| {
             self.logger.send_exit();
             h.join().ok()
         });
@@ -255,7 +921,7 @@ impl JoinHandle {
 
 impl Drop for JoinHandle {
     fn drop(&mut self) {
-        #[cfg(any(feature = "dbg"))]
+        #[cfg(feature = "dbg")]
         dbg!(self.join_handle.is_some());
         self.join()
     }
@@ -264,20 +930,43 @@ impl Drop for JoinHandle {
 struct NonblockLoggerGlobal(Arc<NonblockLogger>);
 
 impl Log for NonblockLoggerGlobal {
-    fn flush(&self) {}
+    fn flush(&self) {
+        self.0.flush();
+    }
 
     fn enabled(&self, metadata: &Metadata) -> bool {
         self.0.filter.enabled(metadata)
     }
 
     fn log(&self, record: &Record) {
-        let g = &self.0;
+        self.0.log_record(record)
+    }
+}
 
-        if g.filter.log(record) {
-            let content = g.formater.format(record);
-            let message = Message::new(content, record.level());
+impl NonblockLogger {
+    fn log_record(&self, record: &Record) {
+        if self.filter.log(record) {
+            self.counters.accepted(record.level());
+            (*self.sendfn)(self, Event::Message(self.message(record)))
+        } else {
+            self.counters.filtered();
+        }
+    }
 
-            (*g.sendfn)(g, Some(message))
+    // with the record if the outputers render or filter it
+    fn message(&self, record: &Record) -> Message {
+        if self.deferred {
+            Message::deferred(OwnedRecord::capture(record))
+        } else if self.structured {
+            let owned = OwnedRecord::capture(record);
+            // the same time and thread as the metadata
+            let mut content = self.pool.get();
+            owned.scope(|| self.formater.format_into(record, &mut content));
+            Message::structured(content, owned)
+        } else {
+            let mut content = self.pool.get();
+            self.formater.format_into(record, &mut content);
+            Message::new(content, record.level())
         }
     }
 }
@@ -293,8 +982,678 @@ impl fmt::Debug for NonblockLogger {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use std::io::{self, BufWriter, Write};
+    use std::sync::MutexGuard;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // records the writes and the reopens
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl Recorder {
+        fn lines(&self) -> Vec<String> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl Write for Recorder {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().push(String::from_utf8_lossy(buf).into_owned());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Outputer for Recorder {
+        fn boxed(self) -> Result<Box<dyn Outputer>, Error> {
+            Ok(Box::new(self) as _)
+        }
+
+        fn desc(&self) -> &str {
+            "recorder"
+        }
+
+        fn reopen(&mut self) -> io::Result<()> {
+            self.0.lock().unwrap().push("reopen".to_owned());
+            Ok(())
+        }
+    }
+
+    // a slow outputer, the writes wait while the gate is held
+    #[derive(Clone)]
+    struct Gated(Recorder, &'static Mutex<()>);
+
+    impl Write for Gated {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let _open = self.1.lock().unwrap();
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Outputer for Gated {
+        fn boxed(self) -> Result<Box<dyn Outputer>, Error> {
+            Ok(Box::new(self) as _)
+        }
+
+        fn desc(&self) -> &str {
+            "gated"
+        }
+
+        fn reopen(&mut self) -> io::Result<()> {
+            self.0.reopen()
+        }
+    }
+
+    // a logger not installed as the global one, for the tests run in parallel
+    fn spawn_local(logger: NonblockLogger) -> (&'static NonblockLogger, JoinHandle) {
+        let handle = logger.start(|logger| Ok(Box::leak(Box::new(logger)))).unwrap();
+        (handle.logger, handle)
+    }
+
+    fn send(logger: &NonblockLogger, level: Level, content: &str) {
+        (*logger.sendfn)(logger, Event::Message(Message::new(content.to_owned(), level)))
+    }
+
+    fn wait_for<F: Fn() -> bool>(f: F) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !f() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        true
+    }
+
+    #[test]
+    fn reopen_after_the_messages_sent_before() {
+        let recorder = Recorder::default();
+        let consumer = BaseConsumer::new().chain(log::LevelFilter::Info, recorder.clone()).unwrap();
+        let (logger, handle) = spawn_local(NonblockLogger::new().consumer(consumer).unwrap());
+
+        send(logger, Level::Info, "1");
+        send(logger, Level::Info, "2");
+        logger.send_reopen();
+        send(logger, Level::Info, "3");
+        assert!(handle.flush());
+
+        assert_eq!(recorder.lines(), ["1", "2", "reopen", "3"]);
+    }
+
+    #[test]
+    fn flush_interval_flushes_the_bufwriter() {
+        let recorder = Recorder::default();
+        let consumer = BaseConsumer::bufwriter(log::LevelFilter::Info, BufWriter::new(recorder.clone()))
+            .flush_interval(Duration::from_millis(20));
+        let (logger, _handle) = spawn_local(NonblockLogger::new().consumer(consumer).unwrap());
+
+        send(logger, Level::Info, "a");
+        send(logger, Level::Info, "b");
+        assert!(wait_for(|| recorder.lines().concat() == "ab"));
+    }
+
+    #[test]
+    fn flush_level_flushes_the_bufwriter() {
+        let recorder = Recorder::default();
+        let consumer =
+            BaseConsumer::bufwriter(log::LevelFilter::Info, BufWriter::new(recorder.clone())).flush_level(Level::Warn);
+        let (logger, _handle) = spawn_local(NonblockLogger::new().consumer(consumer).unwrap());
+
+        send(logger, Level::Info, "a");
+        thread::sleep(Duration::from_millis(50));
+        assert!(recorder.lines().is_empty());
+
+        send(logger, Level::Warn, "w");
+        assert!(wait_for(|| recorder.lines().concat() == "aw"));
+    }
+
+    #[test]
+    fn flush_waits_for_the_bufwriter() {
+        let shared = Shared::default();
+        let consumer = BaseConsumer::bufwriter(log::LevelFilter::Info, BufWriter::new(shared.clone()));
+        let handle = NonblockLogger::new().consumer(consumer).unwrap().spawn().unwrap();
+
+        info!("flushed");
+        assert!(handle.flush_timeout(Duration::from_secs(5)));
+        assert!(String::from_utf8_lossy(&shared.0.lock().unwrap()).ends_with("-- flushed\n"));
+
+        info!("flushed again");
+        log::logger().flush();
+        assert!(String::from_utf8_lossy(&shared.0.lock().unwrap()).ends_with("-- flushed again\n"));
+
+        assert_eq!(stats().accepted(Level::Info), 2);
+    }
+
+    #[test]
+    fn flush_stops_waiting_if_the_consumer_panicked() {
+        for transport in [Transport::Channel, Transport::PerThread] {
+            let logger = NonblockLogger::with_capacity(16).transport(transport).quiet();
+            let (logger, _handle, _recorder, closed) =
+                stalled_with(logger, |consumer| consumer.chain(log::LevelFilter::Trace, Broken).unwrap());
+
+            let flusher = thread::spawn(move || logger.flush());
+            thread::sleep(Duration::from_millis(30));
+            assert!(!flusher.is_finished());
+
+            // `Broken` panics the consumer thread with the flush queued
+            drop(closed);
+            assert!(!flusher.join().unwrap());
+            assert!(logger.exited());
+        }
+    }
+
+    fn log_record(logger: &NonblockLogger, target: &str) {
+        logger.log_record(
+            &Record::builder()
+                .args(format_args!("counted"))
+                .level(Level::Info)
+                .target(target)
+                .build(),
+        );
+    }
+
+    #[test]
+    fn stats_count_the_messages() {
+        let logger = NonblockLogger::with_capacity(2)
+            .backpressure(Backpressure::DropNewest)
+            .filter(BaseFilter::new().chain("skipped", log::LevelFilter::Off))
+            .unwrap();
+        let (logger, handle, recorder, closed) = stalled(logger);
+        (0..3).for_each(|_| log_record(logger, "app"));
+        log_record(logger, "skipped");
+
+        drop(closed);
+        assert!(handle.flush());
+
+        let stats = logger.stats();
+        assert_eq!(stats.accepted(Level::Info), 3);
+        assert_eq!((stats.filtered, stats.dropped, stats.high_water), (1, 1, 2));
+        assert_eq!(stats.outputers[0].desc, "gated");
+        assert_eq!(stats.outputers[0].written as usize, recorder.lines().concat().len());
+        assert_eq!(stats.write_errors, 0);
+    }
+
+    // a logger of capacity 2, its consumer thread stalled by the gate writing "0"
+    fn stalled(logger: NonblockLogger) -> (&'static NonblockLogger, JoinHandle, Recorder, MutexGuard<'static, ()>) {
+        stalled_with(logger, |consumer| consumer)
+    }
+
+    // the gated outputer first, then the others chained by `chain`
+    fn stalled_with<F>(
+        logger: NonblockLogger,
+        chain: F,
+    ) -> (&'static NonblockLogger, JoinHandle, Recorder, MutexGuard<'static, ()>)
+    where
+        F: FnOnce(BaseConsumer) -> BaseConsumer,
+    {
+        let (recorder, gate): (_, &'static Mutex<()>) = (Recorder::default(), Box::leak(Box::default()));
+        let consumer = BaseConsumer::new()
+            .chain(log::LevelFilter::Trace, Gated(recorder.clone(), gate))
+            .unwrap();
+        let consumer = chain(consumer);
+        let closed = gate.lock().unwrap();
+        let (logger, handle) = spawn_local(logger.consumer(consumer).unwrap());
+
+        send(logger, Level::Info, "0");
+        assert!(wait_for(|| logger.messages_in_channel() == 0));
+        (logger, handle, recorder, closed)
+    }
+
+    fn dropped_line(line: &str) -> bool {
+        line.ends_with(" messages dropped\n")
+    }
+
+    #[test]
+    fn backpressure_panic_or_quiet() {
+        for quiet in [false, true] {
+            let logger = NonblockLogger::with_capacity(2);
+            let (logger, handle, _recorder, closed) = stalled(if quiet { logger.quiet() } else { logger });
+            send(logger, Level::Info, "1");
+            send(logger, Level::Info, "2");
+            assert_eq!(thread::spawn(move || send(logger, Level::Info, "3")).join().is_err(), !quiet);
+            assert_eq!(logger.stats().dropped, 1);
+
+            // the exit of the handle panics too if the channel is full
+            drop(closed);
+            assert!(handle.flush());
+        }
+    }
+
+    #[test]
+    fn backpressure_drop_newest() {
+        let logger = NonblockLogger::with_capacity(2).backpressure(Backpressure::DropNewest);
+        let (logger, handle, recorder, closed) = stalled(logger);
+        for content in ["1", "2", "3", "4"] {
+            send(logger, Level::Info, content);
+        }
+        assert_eq!(logger.stats().dropped, 2);
+
+        drop(closed);
+        assert!(wait_for(|| logger.messages_in_channel() == 0));
+        send(logger, Level::Info, "5");
+        assert!(handle.flush());
+
+        let lines = recorder.lines();
+        assert_eq!(lines[..3], ["0", "1", "2"]);
+        assert!(lines[3].ends_with(" 2 messages dropped\n"), "{:?}", lines);
+        assert_eq!(lines[4..], ["5"]);
+    }
+
+    #[test]
+    fn backpressure_drop_oldest_keeps_the_others() {
+        let logger = NonblockLogger::with_capacity(2).backpressure(Backpressure::DropOldest);
+        let (logger, handle, recorder, closed) = stalled(logger);
+        logger.send_reopen();
+        send(logger, Level::Info, "1");
+        send(logger, Level::Info, "2");
+        send(logger, Level::Info, "3");
+        assert_eq!(logger.stats().dropped, 2);
+
+        drop(closed);
+        assert!(handle.flush());
+        assert_eq!(recorder.lines(), ["0", "reopen", "3"]);
+    }
+
+    #[test]
+    fn backpressure_drop_oldest_per_thread_drops_the_newest() {
+        let logger = NonblockLogger::with_capacity(2)
+            .transport(Transport::PerThread)
+            .backpressure(Backpressure::DropOldest);
+        let (logger, handle, recorder, closed) = stalled(logger);
+        send(logger, Level::Info, "1");
+        send(logger, Level::Info, "2");
+        send(logger, Level::Info, "3");
+        assert_eq!(logger.stats().dropped, 1);
+
+        drop(closed);
+        assert!(handle.flush());
+        assert_eq!(recorder.lines(), ["0", "1", "2"]);
+    }
+
+    #[test]
+    fn backpressure_block_until_timeout() {
+        let timeout = Duration::from_millis(30);
+        let logger = NonblockLogger::with_capacity(2).backpressure(Backpressure::Block(Some(timeout)));
+        let (logger, _handle, _recorder, _closed) = stalled(logger);
+        send(logger, Level::Info, "1");
+        send(logger, Level::Info, "2");
+
+        let now = Instant::now();
+        send(logger, Level::Info, "3");
+        assert!(now.elapsed() >= timeout);
+        assert_eq!(logger.stats().dropped, 1);
+    }
+
+    #[test]
+    fn backpressure_block_until_caught_up() {
+        let logger = NonblockLogger::with_capacity(2).backpressure(Backpressure::Block(None));
+        let (logger, handle, recorder, closed) = stalled(logger);
+        send(logger, Level::Info, "1");
+        send(logger, Level::Info, "2");
+
+        let sender = thread::spawn(move || send(logger, Level::Info, "3"));
+        thread::sleep(Duration::from_millis(30));
+        assert!(!sender.is_finished());
+
+        drop(closed);
+        sender.join().unwrap();
+        assert!(handle.flush());
+        assert_eq!(recorder.lines(), ["0", "1", "2", "3"]);
+        assert_eq!(logger.stats().dropped, 0);
+    }
+
+    #[test]
+    fn backpressure_drop_below() {
+        let logger = NonblockLogger::with_capacity(2).backpressure(Backpressure::DropBelow(Level::Warn));
+        let (logger, handle, recorder, closed) = stalled(logger);
+        send(logger, Level::Info, "1");
+        send(logger, Level::Info, "2");
+        send(logger, Level::Info, "3");
+        assert_eq!(logger.stats().dropped, 1);
+
+        let sender = thread::spawn(move || send(logger, Level::Warn, "4"));
+        thread::sleep(Duration::from_millis(30));
+        assert!(!sender.is_finished());
+
+        drop(closed);
+        sender.join().unwrap();
+        send(logger, Level::Info, "5");
+        assert!(handle.flush());
+
+        let lines = recorder.lines();
+        assert_eq!(lines[..4], ["0", "1", "2", "4"]);
+        assert!(dropped_line(&lines[4]), "{:?}", lines);
+        assert_eq!(lines[5..], ["5"]);
+    }
+
+    // logs from the consumer thread, which can't wait for itself
+    #[derive(Clone)]
+    struct Echo(Recorder, Arc<std::sync::OnceLock<&'static NonblockLogger>>, usize);
+
+    impl Echo {
+        fn new(echoes: usize) -> Self {
+            Echo(Recorder::default(), Arc::default(), echoes)
+        }
+    }
+
+    impl Write for Echo {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if buf == b"echo" {
+                let logger = self.1.get().unwrap();
+                (0..self.2).for_each(|_| send(logger, Level::Info, "e"));
+            }
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Outputer for Echo {
+        fn boxed(self) -> Result<Box<dyn Outputer>, Error> {
+            Ok(Box::new(self) as _)
+        }
+
+        fn desc(&self) -> &str {
+            "echo"
+        }
+    }
+
+    #[test]
+    fn backpressure_never_blocks_the_consumer_thread() {
+        let echo = Echo::new(4);
+        let consumer = BaseConsumer::new().chain(log::LevelFilter::Info, echo.clone()).unwrap();
+        let logger = NonblockLogger::with_capacity(2).backpressure(Backpressure::Block(None));
+        let (logger, handle) = spawn_local(logger.consumer(consumer).unwrap());
+        echo.1.set(logger).ok();
+
+        send(logger, Level::Info, "echo");
+        assert!(wait_for(|| logger.stats().dropped >= 2));
+        assert!(handle.flush_timeout(Duration::from_secs(5)));
+
+        // the echoes beyond the capacity are dropped instead of blocking
+        let lines = echo.0.lines();
+        assert_eq!(lines[0], "echo");
+        assert_eq!(lines.len() - 1 + logger.stats().dropped as usize, 4);
+    }
+
+    // a consumer owning the receiver by the iterator
+    struct Collect(Recorder);
+
+    impl Consumer for Collect {
+        fn boxed(self) -> Result<Box<dyn Consumer>, Error> {
+            Ok(Box::new(self) as _)
+        }
+
+        fn consume(&mut self, channel: Receiver) {
+            for event in channel {
+                match event {
+                    Event::Message(message) => self.0 .0.lock().unwrap().push(message.content),
+                    Event::Flush(done) => drop(done.send(())),
+                    Event::Exit => break,
+                    Event::Reopen => {}
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn split_by_the_levels() {
+        let (out, err) = (Recorder::default(), Recorder::default());
+        let consumer = split(out.clone(), err.clone()).unwrap();
+        let (logger, handle) = spawn_local(NonblockLogger::new().consumer(consumer).unwrap());
+
+        for (level, content) in [
+            (Level::Trace, "t"),
+            (Level::Debug, "d"),
+            (Level::Info, "i"),
+            (Level::Warn, "w"),
+            (Level::Error, "e"),
+        ] {
+            send(logger, level, content);
+        }
+        assert!(handle.flush());
+        assert_eq!(out.lines(), ["t", "d", "i"]);
+        assert_eq!(err.lines(), ["w", "e"]);
+    }
+
+    #[test]
+    fn consume_the_receiver_by_value() {
+        let recorder = Recorder::default();
+        let (logger, handle) = spawn_local(NonblockLogger::new().consumer(Collect(recorder.clone())).unwrap());
+
+        send(logger, Level::Info, "a");
+        send(logger, Level::Info, "b");
+        assert!(handle.flush());
+        assert_eq!(recorder.lines(), ["a", "b"]);
+    }
+
+    #[test]
+    fn formaters_render_the_dropped_line() {
+        let formatted = Recorder::default();
+        let logger = NonblockLogger::with_capacity(2).backpressure(Backpressure::DropNewest);
+        let (logger, handle, recorder, closed) = stalled_with(logger, |consumer| {
+            consumer
+                .chain(log::LevelFilter::Trace, formatted.clone())
+                .unwrap()
+                .formater(JsonFormater::new())
+        });
+        assert!(logger.structured_get());
+        (1..4).for_each(|i| send(logger, Level::Info, &i.to_string()));
+
+        drop(closed);
+        assert!(wait_for(|| logger.messages_in_channel() == 0));
+        log_record(logger, "app");
+        assert!(handle.flush());
+
+        // the messages without records are skipped by the outputers of their own formaters
+        let lines = formatted.lines();
+        assert_eq!(lines.len(), 2, "{:?}", lines);
+        assert!(
+            lines[0].starts_with('{') && lines[0].contains("1 messages dropped"),
+            "{:?}",
+            lines
+        );
+        assert!(lines[1].contains("counted"), "{:?}", lines);
+        assert_eq!(recorder.lines().len(), 5);
+    }
+
+    #[test]
+    fn unbounded_per_thread_never_blocks_the_consumer_thread() {
+        let echo = Echo::new(2000);
+        let consumer = BaseConsumer::new().chain(log::LevelFilter::Info, echo.clone()).unwrap();
+        let logger = NonblockLogger::new().transport(Transport::PerThread);
+        let (logger, handle) = spawn_local(logger.consumer(consumer).unwrap());
+        echo.1.set(logger).ok();
+
+        send(logger, Level::Info, "echo");
+        assert!(wait_for(|| logger.stats().dropped > 0));
+        assert!(handle.flush_timeout(Duration::from_secs(5)));
+
+        let lines = echo.0.lines();
+        assert_eq!(lines.len() - 1 + logger.stats().dropped as usize, 2000);
+    }
+
+    #[test]
+    fn priority_lane_drained_first() {
+        let logger = NonblockLogger::with_capacity(4).priority(Level::Warn, 1);
+        let (logger, handle, recorder, closed) = stalled(logger);
+        send(logger, Level::Info, "1");
+        send(logger, Level::Error, "e");
+        // the lane is full
+        send(logger, Level::Warn, "w");
+        send(logger, Level::Info, "2");
+        assert_eq!(logger.messages_in_channel(), 4);
+
+        drop(closed);
+        assert!(handle.flush());
+        assert_eq!(recorder.lines(), ["0", "e", "1", "w", "2"]);
+
+        let logger = NonblockLogger::new().priority(Level::Warn, 0);
+        assert!(logger.priority_get().is_none());
+    }
+
+    #[test]
+    fn max_bytes_budget() {
+        let logger = NonblockLogger::new().max_bytes(10).backpressure(Backpressure::DropNewest);
+        let (logger, handle, recorder, closed) = stalled(logger);
+        send(logger, Level::Info, "12345");
+        send(logger, Level::Info, "67890");
+        send(logger, Level::Info, "x");
+        send(logger, Level::Info, "oversize oversize");
+        assert_eq!((logger.bytes_in_channel(), logger.stats().dropped), (10, 2));
+
+        // an oversize message is sent only when the channel is empty
+        drop(closed);
+        assert!(wait_for(|| logger.bytes_in_channel() == 0));
+        logger.send_dropped();
+        assert!(wait_for(|| logger.bytes_in_channel() == 0));
+        send(logger, Level::Info, "oversize oversize");
+        send(logger, Level::Info, "y");
+        assert!(handle.flush());
+
+        let lines = recorder.lines();
+        assert_eq!(lines[..3], ["0", "12345", "67890"]);
+        assert!(dropped_line(&lines[3]), "{:?}", lines);
+        assert_eq!(lines[4], "oversize oversize");
+        // "y" is dropped unless the oversize one was received first
+        assert!(lines.len() == 5 || lines[5] == "y", "{:?}", lines);
+        assert_eq!(logger.bytes_in_channel(), 0);
+    }
+
+    #[test]
+    fn max_bytes_panic_or_quiet() {
+        for transport in [Transport::Channel, Transport::PerThread] {
+            for quiet in [false, true] {
+                let logger = NonblockLogger::new().transport(transport).max_bytes(10);
+                let (logger, handle, _recorder, closed) = stalled(if quiet { logger.quiet() } else { logger });
+                send(logger, Level::Info, "12345");
+                send(logger, Level::Info, "67890");
+                assert_eq!(thread::spawn(move || send(logger, Level::Info, "x")).join().is_err(), !quiet);
+                assert_eq!(logger.stats().dropped, 1);
+
+                drop(closed);
+                assert!(handle.flush());
+            }
+        }
+    }
+
+    // panics the consumer thread by the default `ErrorPolicy::Panic`
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("broken"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Outputer for Broken {
+        fn boxed(self) -> Result<Box<dyn Outputer>, Error> {
+            Ok(Box::new(self) as _)
+        }
+
+        fn desc(&self) -> &str {
+            "broken"
+        }
+    }
+
+    #[test]
+    fn max_bytes_stops_waiting_if_the_consumer_panicked() {
+        let logger = NonblockLogger::new()
+            .max_bytes(10)
+            .backpressure(Backpressure::Block(None))
+            .quiet();
+        let (logger, _handle, _recorder, closed) =
+            stalled_with(logger, |consumer| consumer.chain(log::LevelFilter::Trace, Broken).unwrap());
+        send(logger, Level::Info, "12345");
+        send(logger, Level::Info, "67890");
+
+        let sender = thread::spawn(move || send(logger, Level::Info, "x"));
+        thread::sleep(Duration::from_millis(30));
+        assert!(!sender.is_finished());
+
+        drop(closed);
+        sender.join().unwrap();
+        assert!(logger.exited());
+        assert_eq!(logger.stats().dropped, 1);
+    }
+
+    fn spool_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("nonblock-logger-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn spool_replayed_while_idle() {
+        for transport in [Transport::Channel, Transport::PerThread] {
+            let path = spool_path(&format!("idle-{:?}", transport));
+            let logger = NonblockLogger::with_capacity(2).transport(transport).spool(&path);
+            let (logger, _handle, recorder, closed) = stalled(logger);
+            (1..10).for_each(|i| send(logger, Level::Info, &i.to_string()));
+            assert_eq!(logger.stats().spooled, 7);
+
+            // drained without the flush or more messages
+            drop(closed);
+            assert!(wait_for(|| recorder.lines().len() == 10));
+            assert!(wait_for(|| logger.messages_in_channel() == 0));
+
+            // spooled while the consumer thread waits for the empty channel, as if the channel was full
+            thread::sleep(Duration::from_millis(20));
+            let event = Event::Message(Message::new("idle".to_owned(), Level::Info));
+            assert!(logger.spill(event, true).is_ok());
+            assert!(wait_for(|| recorder.lines().len() == 11), "{:?}", transport);
+            assert_eq!(recorder.lines()[10], "idle");
+
+            std::fs::remove_file(path).ok();
+        }
+    }
+
+    #[test]
+    fn spool_replayed_before_reopen() {
+        let path = spool_path("reopen");
+        let (logger, handle, recorder, closed) = stalled(NonblockLogger::with_capacity(2).spool(&path));
+        send(logger, Level::Info, "1");
+        // as if the channel was full, the later messages are spooled too
+        assert!(logger
+            .spill(Event::Message(Message::new("2".to_owned(), Level::Info)), true)
+            .is_ok());
+        send(logger, Level::Info, "3");
+        logger.send_reopen();
+        assert_eq!(logger.stats().spooled, 2);
+
+        drop(closed);
+        assert!(handle.flush());
+        assert_eq!(recorder.lines(), ["0", "1", "2", "3", "reopen"]);
+
+        std::fs::remove_file(path).ok();
+    }
 }
@@ -0,0 +1,179 @@
+use crate::formater::{current_thread_name, lowercase, Escape, Formater, Timestamp};
+use log::Record;
+use std::fmt::Write;
+
+impl Formater for LogfmtFormater {
+    fn boxed(self) -> Box<dyn Formater> {
+        Box::new(self) as _
+    }
+
+    fn format(&self, record: &Record) -> String {
+        let mut line = String::with_capacity(256);
+        self.format_into(record, &mut line);
+        line
+    }
+
+    fn format_into(&self, record: &Record, line: &mut String) {
+        let start = line.len();
+
+        for field in self.fields.iter() {
+            let sep = line.len() > start;
+            match field {
+                LogfmtField::Level => push_pair(line, sep, "level", lowercase(record.level())),
+                LogfmtField::Time => {
+                    let ts = Timestamp::new(self.datetime.as_deref(), self.local).to_string();
+                    push_pair(line, sep, "ts", &ts)
+                }
+                LogfmtField::Target => push_pair(line, sep, "target", record.target()),
+                LogfmtField::Module => push_pair(line, sep, "module", record.module_path().unwrap_or("*")),
+                LogfmtField::Caller => push_pair(
+                    line,
+                    sep,
+                    "caller",
+                    &format!("{}:{}", record.file().unwrap_or("*"), record.line().unwrap_or(0)),
+                ),
+                LogfmtField::Thread => current_thread_name(|ctn| push_pair(line, sep, "thread", ctn)),
+                LogfmtField::Message => match record.args().as_str() {
+                    Some(msg) => push_pair(line, sep, "msg", msg),
+                    None => push_pair(line, sep, "msg", &record.args().to_string()),
+                },
+            }
+        }
+
+        #[cfg(feature = "kv")]
+        crate::kv::push_pairs(line, record.key_values());
+
+        line.push('\n');
+    }
+}
+
+/// The fields of `LogfmtFormater`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogfmtField {
+    /// `level=info`
+    Level,
+    /// `ts=2020-01-01T00:00:00.000Z`
+    Time,
+    /// `target=app::db`
+    Target,
+    /// `module=app::db`
+    Module,
+    /// `caller=src/db.rs:42`
+    Caller,
+    /// `thread=1.main`
+    Thread,
+    /// `msg="connected to db"`
+    Message,
+}
+
+/// `key=value` pairs per line, the values are quoted if needed
+#[derive(Debug, Clone)]
+pub struct LogfmtFormater {
+    local: bool,
+    datetime: Option<String>,
+    fields: Vec<LogfmtField>,
+}
+
+impl Default for LogfmtFormater {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LogfmtFormater {
+    pub fn new() -> Self {
+        Self {
+            local: false,
+            datetime: None,
+            fields: vec![
+                LogfmtField::Level,
+                LogfmtField::Time,
+                LogfmtField::Target,
+                LogfmtField::Caller,
+                LogfmtField::Thread,
+                LogfmtField::Message,
+            ],
+        }
+    }
+
+    pub fn local(mut self, local: bool) -> Self {
+        self.local = local;
+        self
+    }
+
+    #[inline]
+    pub fn local_get(&self) -> bool {
+        self.local
+    }
+
+    /// the chrono format of `ts`, RFC 3339 with milliseconds default
+    pub fn datetime<S: Into<String>>(mut self, datetime: S) -> Self {
+        self.datetime = Some(datetime.into());
+        self
+    }
+
+    #[inline]
+    pub fn datetime_get(&self) -> Option<&str> {
+        self.datetime.as_deref()
+    }
+
+    /// the fields written, in this order
+    pub fn fields<F: AsRef<[LogfmtField]>>(mut self, fields: F) -> Self {
+        self.fields = fields.as_ref().to_vec();
+        self
+    }
+
+    #[inline]
+    pub fn fields_get(&self) -> &[LogfmtField] {
+        &self.fields
+    }
+}
+
+pub(crate) fn push_pair(line: &mut String, sep: bool, key: &str, value: &str) {
+    if sep {
+        line.push(' ');
+    }
+    line.push_str(key);
+    line.push('=');
+    push_value(line, value);
+}
+
+/// push the value, quoted and escaped if it is empty or contains spaces, `=`, `"` or control characters
+pub(crate) fn push_value(line: &mut String, value: &str) {
+    let quote = value.is_empty() || value.chars().any(|c| c <= ' ' || c == '=' || c == '"' || c == '\\');
+    if !quote {
+        return line.push_str(value);
+    }
+
+    line.push('"');
+    Escape(line).write_str(value).unwrap();
+    line.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn logfmt_formater_quotes() {
+        let record = Record::builder()
+            .args(format_args!("a=b \"c\"\n"))
+            .level(Level::Info)
+            .target("logfmt")
+            .file_static(Some("src/logfmt.rs"))
+            .line(Some(7))
+            .build();
+
+        let formater = LogfmtFormater::new().fields([
+            LogfmtField::Message,
+            LogfmtField::Level,
+            LogfmtField::Caller,
+            LogfmtField::Target,
+        ]);
+        assert_eq!(
+            formater.format(&record),
+            "msg=\"a=b \\\"c\\\"\\n\" level=info caller=src/logfmt.rs:7 target=logfmt\n"
+        );
+    }
+}
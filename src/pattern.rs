@@ -0,0 +1,303 @@
+use crate::formater::{current_thread_name, current_time, Formater};
+use crate::Error;
+use chrono::format::{Item, StrftimeItems};
+use log::Record;
+use std::fmt::Write;
+
+impl Formater for PatternFormater {
+    fn boxed(self) -> Box<dyn Formater> {
+        Box::new(self) as _
+    }
+
+    fn format(&self, record: &Record) -> String {
+        let mut line = String::with_capacity(256);
+        self.format_into(record, &mut line);
+        line
+    }
+
+    fn format_into(&self, record: &Record, line: &mut String) {
+        let mut field = String::new();
+
+        for segment in self.segments.iter() {
+            match segment {
+                Segment::Literal(literal) => line.push_str(literal),
+                Segment::Field(piece, spec) if spec.is_none() => self.push(line, *piece, record),
+                Segment::Field(piece, spec) => {
+                    field.clear();
+                    self.push(&mut field, *piece, record);
+                    spec.push(line, &field);
+                }
+            }
+        }
+    }
+}
+
+/// A formater compiled from a pattern such as `"{d(%H:%M:%S%.3f)} {l:5} [{T}] {f}:{L} [{t}] -- {m}{n}"`
+///
+/// `{d}` or `{d(strftime)}` the time, `{l}` level, `{T}` thread, `{t}` target, `{M}` module,
+/// `{f}` file, `{L}` line, `{m}` message, `{k}` the key-values with a leading space, `{n}` newline, `{{` and `}}` the braces.
+///
+/// The fields can be specified as `{t:<20}`, `{t:>20}` for the width and alignment, `{t:.20}` for the truncation, or `{t:>20.20}`
+#[derive(Debug, Clone)]
+pub struct PatternFormater {
+    pattern: String,
+    local: bool,
+    segments: Vec<Segment>,
+    datetimes: Vec<Vec<Item<'static>>>,
+}
+
+impl PatternFormater {
+    pub fn new<S: Into<String>>(pattern: S) -> Result<Self, Error> {
+        let pattern = pattern.into();
+        let (segments, datetimes) = parse(&pattern)?;
+
+        Ok(Self {
+            pattern,
+            local: false,
+            segments,
+            datetimes,
+        })
+    }
+
+    pub fn local(mut self, local: bool) -> Self {
+        self.local = local;
+        self
+    }
+
+    #[inline]
+    pub fn local_get(&self) -> bool {
+        self.local
+    }
+
+    #[inline]
+    pub fn pattern_get(&self) -> &str {
+        &self.pattern
+    }
+
+    fn push(&self, line: &mut String, piece: Piece, record: &Record) {
+        match piece {
+            Piece::Datetime(idx) => {
+                let items = self.datetimes[idx].iter();
+                let now = current_time();
+                if self.local {
+                    write!(line, "{}", now.with_timezone(&chrono::Local).format_with_items(items))
+                } else {
+                    write!(line, "{}", now.format_with_items(items))
+                }
+                .unwrap()
+            }
+            Piece::Level => line.push_str(record.level().as_str()),
+            Piece::Thread => current_thread_name(|ctn| line.push_str(ctn)),
+            Piece::Target => line.push_str(record.target()),
+            Piece::Module => line.push_str(record.module_path().unwrap_or("*")),
+            Piece::File => line.push_str(record.file().unwrap_or("*")),
+            Piece::Line => write!(line, "{}", record.line().unwrap_or(0)).unwrap(),
+            Piece::Message => write!(line, "{}", record.args()).unwrap(),
+            Piece::KeyValues => {
+                #[cfg(feature = "kv")]
+                crate::kv::push_pairs(line, record.key_values());
+            }
+            Piece::Newline => line.push('\n'),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Literal(String),
+    Field(Piece, Spec),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Piece {
+    // the index of `PatternFormater::datetimes`
+    Datetime(usize),
+    Level,
+    Thread,
+    Target,
+    Module,
+    File,
+    Line,
+    Message,
+    KeyValues,
+    Newline,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Spec {
+    right: bool,
+    width: usize,
+    max: Option<usize>,
+}
+
+impl Spec {
+    fn is_none(&self) -> bool {
+        self.width == 0 && self.max.is_none()
+    }
+
+    fn push(&self, line: &mut String, field: &str) {
+        let field = match self.max.and_then(|max| field.char_indices().nth(max)) {
+            Some((idx, _)) => &field[..idx],
+            None => field,
+        };
+
+        let pad = self.width.saturating_sub(field.chars().count());
+        if self.right {
+            line.extend((0..pad).map(|_| ' '));
+            line.push_str(field);
+        } else {
+            line.push_str(field);
+            line.extend((0..pad).map(|_| ' '));
+        }
+    }
+}
+
+const DATETIME: &str = "%Y-%m-%d %H:%M:%S%.3f";
+
+fn parse(pattern: &str) -> Result<(Vec<Segment>, Vec<Vec<Item<'static>>>), Error> {
+    let mut segments = Vec::new();
+    let mut datetimes = Vec::new();
+    let mut literal = String::new();
+    let mut chars = pattern.char_indices().peekable();
+
+    while let Some((pos, c)) = chars.next() {
+        match c {
+            '{' if chars.peek().map(|c| c.1) == Some('{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.peek().map(|c| c.1) == Some('}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '}' => return Err(Error::pattern(pattern, pos, "unmatched '}'")),
+            '{' => {
+                let end = pattern[pos..]
+                    .find('}')
+                    .map(|end| pos + end)
+                    .ok_or_else(|| Error::pattern(pattern, pos, "unclosed '{'"))?;
+                // the strftime may contains ':', but not '}'
+                let field = &pattern[pos + 1..end];
+                let (name, spec) = match field.rfind(':').filter(|&idx| idx > field.rfind(')').unwrap_or(0)) {
+                    Some(idx) => (
+                        &field[..idx],
+                        parse_spec(&field[idx + 1..]).ok_or_else(|| Error::pattern(pattern, pos + 1 + idx, "invalid spec"))?,
+                    ),
+                    None => (field, Spec::default()),
+                };
+
+                let piece = match name {
+                    "d" => Piece::Datetime(parse_datetime(DATETIME, &mut datetimes)),
+                    "l" => Piece::Level,
+                    "T" => Piece::Thread,
+                    "t" => Piece::Target,
+                    "M" => Piece::Module,
+                    "f" => Piece::File,
+                    "L" => Piece::Line,
+                    "m" => Piece::Message,
+                    "k" => Piece::KeyValues,
+                    "n" => Piece::Newline,
+                    datetime if datetime.starts_with("d(") && datetime.ends_with(')') => {
+                        let datetime = &datetime[2..datetime.len() - 1];
+                        if StrftimeItems::new(datetime).any(|i| i == Item::Error) {
+                            return Err(Error::pattern(pattern, pos + 3, "invalid strftime"));
+                        }
+                        Piece::Datetime(parse_datetime(datetime, &mut datetimes))
+                    }
+                    _ => return Err(Error::pattern(pattern, pos + 1, "unknown field")),
+                };
+
+                if !literal.is_empty() {
+                    segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                }
+                segments.push(Segment::Field(piece, spec));
+
+                while chars.peek().map(|c| c.0 <= end).unwrap_or(false) {
+                    chars.next();
+                }
+            }
+            c => literal.push(c),
+        }
+    }
+
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+
+    Ok((segments, datetimes))
+}
+
+fn parse_datetime(datetime: &str, datetimes: &mut Vec<Vec<Item<'static>>>) -> usize {
+    datetimes.push(StrftimeItems::new(datetime).map(|i| i.to_owned()).collect());
+    datetimes.len() - 1
+}
+
+// `[<>]?[0-9]*(.[0-9]+)?`
+fn parse_spec(spec: &str) -> Option<Spec> {
+    let (right, spec) = match spec.as_bytes().first() {
+        Some(b'>') => (true, &spec[1..]),
+        Some(b'<') => (false, &spec[1..]),
+        _ => (false, spec),
+    };
+
+    let (width, max) = match spec.find('.') {
+        Some(idx) => (&spec[..idx], Some(&spec[idx + 1..])),
+        None => (spec, None),
+    };
+
+    let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    if !(width.is_empty() || digits(width)) || !max.map(digits).unwrap_or(true) {
+        return None;
+    }
+
+    // too large to parse is invalid too
+    Some(Spec {
+        right,
+        width: if width.is_empty() { 0 } else { width.parse().ok()? },
+        max: max.map(str::parse).transpose().ok()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OwnedRecord;
+    use log::Level;
+
+    #[test]
+    fn pattern_formater_specs() {
+        let record = Record::builder()
+            .args(format_args!("{{hi}}"))
+            .level(Level::Info)
+            .target("pattern::target")
+            .file_static(Some("src/pattern.rs"))
+            .line(Some(7))
+            .build();
+        let owned = OwnedRecord::capture(&record);
+
+        let formater = PatternFormater::new("{d(%H:%M:%S)} {l:5}|{l:>5}|{t:.7}|{t:>9.7}|[{T}] {f}:{L} -- {m} {{}}{n}").unwrap();
+        assert_eq!(
+            owned.scope(|| formater.format(&record)),
+            format!(
+                "{} INFO | INFO|pattern|  pattern|[{}] src/pattern.rs:7 -- {{hi}} {{}}\n",
+                owned.time.format("%H:%M:%S"),
+                owned.thread
+            )
+        );
+
+        for bad in [
+            "{x}",
+            "{l",
+            "l}",
+            "{l:5x}",
+            "{d(%Q)}",
+            "{t:.}",
+            "{t:.99999999999999999999}",
+            "{t:99999999999999999999}",
+        ]
+        .iter()
+        {
+            assert!(PatternFormater::new(*bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use crossbeam_channel as channel;
+
+/// The emptied buffers handed back by the consumer thread, for the producers to format into
+#[derive(Debug)]
+pub(crate) struct Pool {
+    sender: channel::Sender<String>,
+    receiver: channel::Receiver<String>,
+}
+
+impl Pool {
+    // don't keep the buffers grown by the huge messages
+    const MAX_CAPACITY: usize = 64 * 1024;
+
+    pub fn new(buffers: usize) -> Self {
+        let (sender, receiver) = channel::bounded(buffers);
+        Self { sender, receiver }
+    }
+
+    #[inline]
+    pub fn get(&self) -> String {
+        self.receiver.try_recv().unwrap_or_default()
+    }
+
+    #[inline]
+    pub fn put(&self, mut buf: String) {
+        if buf.capacity() > 0 && buf.capacity() <= Self::MAX_CAPACITY {
+            buf.clear();
+            self.sender.try_send(buf).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pool_recycles_buffers() {
+        let pool = Pool::new(1);
+        let mut buf = pool.get();
+        buf.push_str("hello");
+        let ptr = buf.as_ptr();
+        pool.put(buf);
+        pool.put(String::with_capacity(8));
+
+        let buf = pool.get();
+        assert!(buf.is_empty());
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_eq!(pool.get().capacity(), 0);
+    }
+}
@@ -0,0 +1,517 @@
+use crate::Event;
+use crossbeam_channel::{self as channel, RecvTimeoutError, Select, SendError, SendTimeoutError, TryRecvError, TrySendError};
+use std::cell::{RefCell, UnsafeCell};
+use std::mem::MaybeUninit;
+use std::sync::atomic::{fence, AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+/// How the messages are sent to the consumer thread
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// one channel shared by all the threads, the default
+    Channel,
+    /// a lock-free queue per producer thread, merged by the sequence number on the consumer thread,
+    /// the capacity of `with_capacity()` is per thread, the queues of `new()` block if full,
+    /// except on the consumer thread, which drops the message
+    PerThread,
+}
+
+// the capacity of the queues per thread for `new()`
+const PER_THREAD: usize = 1024;
+
+/// The sending side of the transport
+pub(crate) enum Queue {
+    Channel(channel::Sender<Event>),
+    Rings(Arc<Rings>),
+}
+
+/// The receiving side of the transport, only used by the consumer thread
+pub(crate) enum Dequeue {
+    Channel(channel::Receiver<Event>),
+    Rings {
+        rings: Arc<Rings>,
+        // the generation and snapshot of `Rings::rings`
+        cached: RefCell<(usize, Vec<Arc<Ring>>)>,
+    },
+}
+
+pub(crate) fn queue(transport: Transport, capacity: Option<usize>) -> (Queue, Dequeue) {
+    match transport {
+        Transport::Channel => {
+            let (mp, mc) = match capacity {
+                Some(cap) => channel::bounded(cap),
+                None => channel::unbounded(),
+            };
+            (Queue::Channel(mp), Dequeue::Channel(mc))
+        }
+        Transport::PerThread => {
+            let rings = Arc::new(Rings::new(capacity));
+            let dequeue = Dequeue::Rings {
+                rings: rings.clone(),
+                cached: RefCell::new((usize::MAX, Vec::new())),
+            };
+            (Queue::Rings(rings), dequeue)
+        }
+    }
+}
+
+impl Queue {
+    pub fn try_send(&self, event: Event) -> Result<(), TrySendError<Event>> {
+        match self {
+            Queue::Channel(mp) => mp.try_send(event),
+            Queue::Rings(rings) => rings.push(event),
+        }
+    }
+
+    pub fn send(&self, event: Event) -> Result<(), SendError<Event>> {
+        match self {
+            Queue::Channel(mp) => mp.send(event),
+            Queue::Rings(rings) => rings.send_deadline(event, None).map_err(|e| SendError(e.into_inner())),
+        }
+    }
+
+    pub fn send_timeout(&self, event: Event, timeout: Duration) -> Result<(), SendTimeoutError<Event>> {
+        match self {
+            Queue::Channel(mp) => mp.send_timeout(event, timeout),
+            Queue::Rings(rings) => rings.send_deadline(event, Some(Instant::now() + timeout)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Queue::Channel(mp) => mp.len(),
+            Queue::Rings(rings) => rings.len(),
+        }
+    }
+
+    /// the capacity of the channel, or the queue of each thread
+    pub fn capacity(&self) -> Option<usize> {
+        match self {
+            Queue::Channel(mp) => mp.capacity(),
+            Queue::Rings(rings) => Some(rings.capacity),
+        }
+    }
+
+    /// wake the consumer thread, after sent to the priority lane
+    pub fn wake(&self) {
+        if let Queue::Rings(rings) = self {
+            rings.wake();
+        }
+    }
+}
+
+impl Dequeue {
+    /// wait for the next event, the events of the priority lane first, returns `None` if woken by `wake`
+    pub fn recv_deadline(
+        &self,
+        priority: Option<&channel::Receiver<Event>>,
+        wake: Option<&channel::Receiver<()>>,
+        deadline: Option<Instant>,
+    ) -> Result<Option<Event>, RecvTimeoutError> {
+        match (self, priority, wake) {
+            (Dequeue::Channel(mc), None, None) => match deadline {
+                Some(deadline) => mc.recv_deadline(deadline).map(Some),
+                None => mc.recv().map(Some).map_err(|_| RecvTimeoutError::Disconnected),
+            },
+            (Dequeue::Channel(mc), priority, wake) => {
+                let mut select = Select::new();
+                if let Some(priority) = priority {
+                    select.recv(priority);
+                }
+                if let Some(wake) = wake {
+                    select.recv(wake);
+                }
+                select.recv(mc);
+
+                loop {
+                    if let Some(Ok(event)) = priority.map(|p| p.try_recv()) {
+                        return Ok(Some(event));
+                    }
+                    match mc.try_recv() {
+                        Ok(event) => return Ok(Some(event)),
+                        Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                        Err(TryRecvError::Empty) => {}
+                    }
+                    if let Some(Ok(())) = wake.map(|w| w.try_recv()) {
+                        return Ok(None);
+                    }
+
+                    match deadline {
+                        Some(deadline) => {
+                            select.ready_deadline(deadline).map_err(|_| RecvTimeoutError::Timeout)?;
+                        }
+                        None => {
+                            select.ready();
+                        }
+                    }
+                }
+            }
+            (Dequeue::Rings { rings, cached }, priority, wake) => {
+                rings.recv_deadline(&mut cached.borrow_mut(), priority, wake, deadline)
+            }
+        }
+    }
+
+    pub fn try_recv(&self) -> Result<Event, TryRecvError> {
+        match self {
+            Dequeue::Channel(mc) => mc.try_recv(),
+            Dequeue::Rings { rings, cached } => rings.try_recv(&mut cached.borrow_mut()).ok_or(TryRecvError::Empty),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Dequeue::Channel(mc) => mc.len(),
+            Dequeue::Rings { rings, .. } => rings.len(),
+        }
+    }
+
+    /// a clone of the channel for `Backpressure::DropOldest`
+    pub fn channel(&self) -> Option<channel::Receiver<Event>> {
+        match self {
+            Dequeue::Channel(mc) => Some(mc.clone()),
+            Dequeue::Rings { .. } => None,
+        }
+    }
+}
+
+impl Drop for Dequeue {
+    fn drop(&mut self) {
+        if let Dequeue::Rings { rings, .. } = self {
+            rings.disconnected.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+pub(crate) struct Rings {
+    id: usize,
+    capacity: usize,
+    // the next sequence number, also the count of the pushed
+    seq: AtomicU64,
+    popped: AtomicU64,
+    rings: Mutex<Vec<Arc<Ring>>>,
+    generation: AtomicUsize,
+    // for the threads can't access their thread locals, such as in the destructors
+    shared: Arc<Ring>,
+    shared_push: Mutex<()>,
+    sleeping: AtomicBool,
+    consumer: Mutex<Option<Thread>>,
+    disconnected: AtomicBool,
+}
+
+struct Producer {
+    rings: usize,
+    ring: Arc<Ring>,
+}
+
+impl Drop for Producer {
+    fn drop(&mut self) {
+        self.ring.gone.store(true, Ordering::Release);
+    }
+}
+
+thread_local!(static PRODUCER: RefCell<Option<Producer>> = const { RefCell::new(None) });
+
+impl Rings {
+    fn new(capacity: Option<usize>) -> Self {
+        static ID: AtomicUsize = AtomicUsize::new(0);
+
+        let capacity = capacity.map(|cap| cap.max(1)).unwrap_or(PER_THREAD);
+        let shared = Arc::new(Ring::new(capacity));
+
+        Self {
+            id: ID.fetch_add(1, Ordering::Relaxed),
+            capacity,
+            seq: AtomicU64::new(0),
+            popped: AtomicU64::new(0),
+            rings: Mutex::new(vec![shared.clone()]),
+            generation: AtomicUsize::new(0),
+            shared,
+            shared_push: Mutex::new(()),
+            sleeping: AtomicBool::new(false),
+            consumer: Mutex::new(None),
+            disconnected: AtomicBool::new(false),
+        }
+    }
+
+    fn len(&self) -> usize {
+        let popped = self.popped.load(Ordering::Relaxed);
+        self.seq.load(Ordering::Relaxed).saturating_sub(popped) as _
+    }
+
+    // call `f` with the queue of the current thread
+    fn with_ring<F, U>(&self, f: F) -> U
+    where
+        F: FnOnce(&Ring) -> U,
+    {
+        let mut f = Some(f);
+        let u = PRODUCER.try_with(|producer| {
+            let mut producer = producer.try_borrow_mut().ok()?;
+            if producer.as_ref().map(|p| p.rings != self.id).unwrap_or(true) {
+                let ring = Arc::new(Ring::new(self.capacity));
+                self.rings.lock().unwrap().push(ring.clone());
+                self.generation.fetch_add(1, Ordering::Release);
+                *producer = Some(Producer { rings: self.id, ring });
+            }
+
+            producer.as_ref().map(|p| (f.take().unwrap())(&p.ring))
+        });
+
+        match u {
+            Ok(Some(u)) => u,
+            _ => {
+                let _push = self.shared_push.lock().unwrap();
+                (f.take().unwrap())(&self.shared)
+            }
+        }
+    }
+
+    fn push(&self, event: Event) -> Result<(), TrySendError<Event>> {
+        if self.disconnected.load(Ordering::Relaxed) {
+            return Err(TrySendError::Disconnected(event));
+        }
+
+        self.with_ring(|ring| {
+            // only this thread pushes, the consumer only makes it less
+            if ring.is_full() {
+                return Err(TrySendError::Full(event));
+            }
+            ring.push(self.seq.fetch_add(1, Ordering::Relaxed), event);
+            Ok(())
+        })?;
+
+        self.wake();
+        Ok(())
+    }
+
+    fn send_deadline(&self, mut event: Event, deadline: Option<Instant>) -> Result<(), SendTimeoutError<Event>> {
+        for spin in 0.. {
+            match self.push(event) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(e)) => event = e,
+                Err(TrySendError::Disconnected(e)) => return Err(SendTimeoutError::Disconnected(e)),
+            }
+
+            if deadline.map(|d| Instant::now() >= d).unwrap_or(false) {
+                return Err(SendTimeoutError::Timeout(event));
+            }
+
+            if spin < 16 {
+                thread::yield_now();
+            } else {
+                thread::sleep(Duration::from_micros(100));
+            }
+        }
+
+        unreachable!()
+    }
+
+    // wake the consumer thread if it's sleeping, after pushed
+    fn wake(&self) {
+        fence(Ordering::SeqCst);
+        if self.sleeping.load(Ordering::Relaxed) && self.sleeping.swap(false, Ordering::Relaxed) {
+            if let Some(consumer) = self.consumer.lock().unwrap().as_ref() {
+                consumer.unpark();
+            }
+        }
+    }
+
+    fn recv_deadline(
+        &self,
+        cached: &mut (usize, Vec<Arc<Ring>>),
+        priority: Option<&channel::Receiver<Event>>,
+        wake: Option<&channel::Receiver<()>>,
+        deadline: Option<Instant>,
+    ) -> Result<Option<Event>, RecvTimeoutError> {
+        let try_recv = |cached: &mut _| priority.and_then(|p| p.try_recv().ok()).or_else(|| self.try_recv(cached));
+        let woken = || wake.map(|w| !w.is_empty()).unwrap_or(false);
+
+        loop {
+            if let Some(event) = try_recv(cached) {
+                return Ok(Some(event));
+            }
+            if let Some(Ok(())) = wake.map(|w| w.try_recv()) {
+                return Ok(None);
+            }
+
+            // sleep after checked the queues again, see `wake`
+            *self.consumer.lock().unwrap() = Some(thread::current());
+            self.sleeping.store(true, Ordering::Relaxed);
+            fence(Ordering::SeqCst);
+
+            let event = try_recv(cached);
+            if event.is_none() && !woken() {
+                self.sweep();
+                match deadline {
+                    Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                        Some(timeout) => thread::park_timeout(timeout),
+                        None => {
+                            self.sleeping.store(false, Ordering::Relaxed);
+                            return Err(RecvTimeoutError::Timeout);
+                        }
+                    },
+                    None => thread::park(),
+                }
+            }
+
+            self.sleeping.store(false, Ordering::Relaxed);
+            if let Some(event) = event {
+                return Ok(Some(event));
+            }
+        }
+    }
+
+    fn try_recv(&self, cached: &mut (usize, Vec<Arc<Ring>>)) -> Option<Event> {
+        let generation = self.generation.load(Ordering::Acquire);
+        if generation != cached.0 {
+            *cached = (generation, self.rings.lock().unwrap().clone());
+        }
+
+        // the message with the least sequence number
+        let mut oldest: Option<(u64, &Ring)> = None;
+        for ring in cached.1.iter().filter(|r| !r.is_empty()) {
+            if let Some(seq) = ring.peek() {
+                if oldest.map(|o| seq < o.0).unwrap_or(true) {
+                    oldest = Some((seq, ring));
+                }
+            }
+        }
+
+        let event = oldest?.1.pop();
+        if event.is_some() {
+            self.popped.fetch_add(1, Ordering::Relaxed);
+        }
+        event
+    }
+
+    // remove the queues of the exited threads
+    fn sweep(&self) {
+        let mut rings = self.rings.lock().unwrap();
+        let len = rings.len();
+        rings.retain(|r| !(r.gone.load(Ordering::Acquire) && r.is_empty()));
+        if rings.len() != len {
+            self.generation.fetch_add(1, Ordering::Release);
+        }
+    }
+}
+
+type Slot = UnsafeCell<MaybeUninit<(u64, Event)>>;
+
+/// A bounded single-producer single-consumer queue
+pub(crate) struct Ring {
+    slots: Box<[Slot]>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    // the producer thread exited
+    gone: AtomicBool,
+}
+
+// the slots between head and tail are only accessed by the producer,
+// the others by the consumer thread, which owns the `Dequeue` not shared with the other threads
+unsafe impl Send for Ring {}
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    fn new(capacity: usize) -> Self {
+        Self {
+            slots: (0..capacity).map(|_| UnsafeCell::new(MaybeUninit::uninit())).collect(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            gone: AtomicBool::new(false),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire) == self.tail.load(Ordering::Acquire)
+    }
+
+    // only called by the producer
+    fn is_full(&self) -> bool {
+        self.tail.load(Ordering::Relaxed) - self.head.load(Ordering::Acquire) >= self.slots.len()
+    }
+
+    // only called by the producer, after checked `is_full`
+    fn push(&self, seq: u64, event: Event) {
+        let tail = self.tail.load(Ordering::Relaxed);
+        unsafe { (*self.slots[tail % self.slots.len()].get()).write((seq, event)) };
+        self.tail.store(tail + 1, Ordering::Release);
+    }
+
+    // only called by the consumer
+    fn peek(&self) -> Option<u64> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        Some(unsafe { (*self.slots[head % self.slots.len()].get()).assume_init_ref().0 })
+    }
+
+    // only called by the consumer
+    fn pop(&self) -> Option<Event> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let (_, event) = unsafe { (*self.slots[head % self.slots.len()].get()).assume_init_read() };
+        self.head.store(head + 1, Ordering::Release);
+        Some(event)
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Message;
+    use log::Level;
+
+    fn message(content: &str) -> Event {
+        Event::Message(Message::new(content.to_owned(), Level::Info))
+    }
+
+    fn content(event: Event) -> String {
+        match event {
+            Event::Message(message) => message.content,
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn per_thread_queues_merged_in_order() {
+        let (queue, dequeue) = queue(Transport::PerThread, Some(2));
+        let queue = Arc::new(queue);
+
+        queue.try_send(message("0")).unwrap();
+        let q = queue.clone();
+        thread::spawn(move || {
+            q.try_send(message("1")).unwrap();
+            q.try_send(message("2")).unwrap();
+            assert!(matches!(q.try_send(message("3")), Err(TrySendError::Full(_))));
+        })
+        .join()
+        .unwrap();
+        queue.try_send(message("4")).unwrap();
+        assert_eq!(queue.len(), 4);
+
+        let q = queue.clone();
+        let sender = thread::spawn(move || q.send(message("5")).unwrap());
+        let received = (0..5)
+            .map(|_| content(dequeue.recv_deadline(None, None, None).unwrap().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(received, ["0", "1", "2", "4", "5"]);
+        sender.join().unwrap();
+
+        let deadline = Instant::now() + Duration::from_millis(10);
+        assert!(dequeue.recv_deadline(None, None, Some(deadline)).is_err());
+        assert_eq!(queue.len(), 0);
+        drop(dequeue);
+        assert!(matches!(queue.try_send(message("6")), Err(TrySendError::Disconnected(_))));
+    }
+}
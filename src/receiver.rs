@@ -0,0 +1,238 @@
+use crate::queue::Dequeue;
+use crate::{Event, Message, NonblockLogger};
+use crossbeam_channel::{self as channel, RecvError, RecvTimeoutError, TryRecvError};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// the spooled messages read at a time
+const REPLAY: usize = 256;
+
+/// The receiving side of the channel for `Consumer`, the deferred messages are formatted here on the consumer thread,
+/// the spooled messages are replayed here once the channel is caught up
+pub struct Receiver {
+    channel: Dequeue,
+    // drained first
+    priority: Option<channel::Receiver<Event>>,
+    // the spooled messages read ahead
+    replayed: RefCell<VecDeque<Event>>,
+    // the reopen, flush or exit received while the spool has messages, and the count of them replayed before it
+    held: RefCell<Option<(usize, Event)>>,
+    logger: &'static NonblockLogger,
+}
+
+impl Receiver {
+    pub(crate) fn new(channel: Dequeue, priority: Option<channel::Receiver<Event>>, logger: &'static NonblockLogger) -> Self {
+        Self {
+            channel,
+            priority,
+            replayed: RefCell::new(VecDeque::new()),
+            held: RefCell::new(None),
+            logger,
+        }
+    }
+
+    pub fn recv(&self) -> Result<Event, RecvError> {
+        self.recv_deadline(None).map_err(|_| RecvError)
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Event, RecvTimeoutError> {
+        self.recv_deadline(Some(Instant::now() + timeout))
+    }
+
+    fn recv_deadline(&self, deadline: Option<Instant>) -> Result<Event, RecvTimeoutError> {
+        // woken to replay the messages spooled while waiting
+        let wake = self.logger.spool.as_ref().map(|s| s.woken());
+        loop {
+            if let Some(event) = self.replay() {
+                return Ok(event);
+            }
+
+            if let Some(event) = self.channel.recv_deadline(self.priority.as_ref(), wake, deadline)? {
+                if let Some(event) = self.received(event) {
+                    return Ok(event);
+                }
+            }
+        }
+    }
+
+    pub fn try_recv(&self) -> Result<Event, TryRecvError> {
+        if let Some(event) = self.replay() {
+            return Ok(event);
+        }
+
+        let event = match self.priority.as_ref().map(|p| p.try_recv()) {
+            Some(Ok(event)) => event,
+            _ => self.channel.try_recv()?,
+        };
+        self.received(event).or_else(|| self.replay()).ok_or(TryRecvError::Empty)
+    }
+
+    pub fn len(&self) -> usize {
+        self.channel.len()
+            + self.priority.as_ref().map(|p| p.len()).unwrap_or(0)
+            + self.replayed.borrow().len()
+            + self.held.borrow().is_some() as usize
+            + self.logger.spool.as_ref().map(|s| s.len()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter(self)
+    }
+
+    #[inline]
+    pub(crate) fn logger(&self) -> &'static NonblockLogger {
+        self.logger
+    }
+
+    /// hand the buffer of the written message back to the producers
+    pub fn recycle(&self, message: Message) {
+        self.logger.pool().put(message.content)
+    }
+
+    // the spooled messages are replayed once the channel is caught up, or before the held event
+    fn replay(&self) -> Option<Event> {
+        let mut replayed = self.replayed.borrow_mut();
+        if replayed.is_empty() {
+            let spool = self.logger.spool.as_ref().filter(|s| s.pending());
+            let held = self.held.borrow_mut().take();
+            match (spool, held) {
+                (Some(spool), Some((before, event))) if before > 0 => {
+                    let lost = spool.replay(|| true, Some(before.min(REPLAY)), &mut replayed);
+                    (0..lost).for_each(|_| self.logger.drop_message());
+
+                    let before = before.saturating_sub(replayed.len() + lost);
+                    if before > 0 && !replayed.is_empty() {
+                        *self.held.borrow_mut() = Some((before, event));
+                    } else {
+                        replayed.push_back(event);
+                    }
+                }
+                (_, Some((_, event))) => replayed.push_back(event),
+                (Some(spool), None) => {
+                    let lost = spool.replay(|| self.channel.len() == 0, Some(REPLAY), &mut replayed);
+                    (0..lost).for_each(|_| self.logger.drop_message());
+                }
+                (None, None) => {}
+            }
+        }
+
+        replayed.pop_front().map(|e| self.format(e))
+    }
+
+    // the messages spooled before the others are replayed first, None if the event is held for them
+    fn received(&self, event: Event) -> Option<Event> {
+        match &event {
+            Event::Message(message) => self.logger.release(message),
+            Event::Reopen | Event::Flush(_) | Event::Exit => {
+                if let Some(spool) = self.logger.spool.as_ref().filter(|s| s.pending()) {
+                    *self.held.borrow_mut() = Some((spool.len(), event));
+                    return None;
+                }
+            }
+        }
+
+        Some(self.format(event))
+    }
+
+    fn format(&self, mut event: Event) -> Event {
+        if let Event::Message(message) = &mut event {
+            if let Some(record) = message.record.as_ref().filter(|_| message.content.is_empty()) {
+                let formater = self.logger.formater_get();
+                let mut content = self.logger.pool().get();
+                record.with_record(|r| formater.format_into(r, &mut content));
+                message.content = content;
+            }
+        }
+
+        event
+    }
+}
+
+pub struct Iter<'a>(&'a Receiver);
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        self.0.recv().ok()
+    }
+}
+
+impl<'a> IntoIterator for &'a Receiver {
+    type Item = Event;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+pub struct IntoIter(Receiver);
+
+impl Iterator for IntoIter {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        self.0.recv().ok()
+    }
+}
+
+impl IntoIterator for Receiver {
+    type Item = Event;
+    type IntoIter = IntoIter;
+
+    fn into_iter(self) -> IntoIter {
+        IntoIter(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    fn message(content: &str) -> Event {
+        Event::Message(Message::new(content.to_owned(), Level::Info))
+    }
+
+    fn content(event: Event) -> String {
+        match event {
+            Event::Message(message) => message.content,
+            Event::Reopen => "reopen".to_owned(),
+            e => panic!("{:?}", e),
+        }
+    }
+
+    #[test]
+    fn spool_replayed_in_chunks_before_reopen() {
+        let path = std::env::temp_dir().join(format!("nonblock-logger-chunks-{}", std::process::id()));
+        let mut logger = NonblockLogger::with_capacity(2).spool(&path);
+        let channel = logger.receiver.get_mut().unwrap().take().unwrap();
+        let logger: &'static NonblockLogger = Box::leak(Box::new(logger));
+        let receiver = Receiver::new(channel, None, logger);
+
+        assert!(logger.try_send(message("first")).is_ok());
+        (0..REPLAY * 2 + 1).for_each(|i| assert!(logger.spill(message(&i.to_string()), true).is_ok()));
+        assert!(logger.try_send(Event::Reopen).is_ok());
+        assert_eq!(content(receiver.recv().unwrap()), "first");
+
+        for i in 0..REPLAY * 2 + 1 {
+            assert_eq!(content(receiver.recv().unwrap()), i.to_string());
+            assert!(receiver.replayed.borrow().len() < REPLAY);
+            // spooled after the reopen received
+            if i == 0 {
+                assert!(logger.spill(message("late"), true).is_ok());
+            }
+        }
+        assert_eq!(content(receiver.recv().unwrap()), "reopen");
+        assert_eq!(content(receiver.recv().unwrap()), "late");
+        assert!(receiver.is_empty());
+
+        std::fs::remove_file(path).ok();
+    }
+}
@@ -0,0 +1,138 @@
+use crate::formater::current_thread;
+#[cfg(feature = "kv")]
+use crate::kv::KeyValues;
+use chrono::{DateTime, Utc};
+use log::{Level, Record};
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::ptr;
+use std::sync::Arc;
+
+/// A snapshot of `log::Record` captured on the caller's thread, with the capture time and thread
+#[derive(Debug, Clone)]
+pub struct OwnedRecord {
+    pub time: DateTime<Utc>,
+    pub level: Level,
+    pub target: Cow<'static, str>,
+    pub module_path: Option<Cow<'static, str>>,
+    pub file: Option<Cow<'static, str>>,
+    pub line: Option<u32>,
+    /// the identity of the capturing thread, `id.name` the same as `current_thread_name`
+    pub thread: Arc<str>,
+    pub args: Cow<'static, str>,
+    #[cfg(feature = "kv")]
+    pub key_values: KeyValues,
+}
+
+thread_local!(static CAPTURED: Cell<*const OwnedRecord> = const { Cell::new(ptr::null()) });
+
+impl OwnedRecord {
+    pub fn capture(record: &Record) -> Self {
+        let module_path = record
+            .module_path_static()
+            .map(Cow::Borrowed)
+            .or_else(|| record.module_path().map(|m| Cow::Owned(m.to_owned())));
+
+        // the target is the module path default
+        let target = match module_path.as_ref() {
+            Some(Cow::Borrowed(m)) if *m == record.target() => Cow::Borrowed(*m),
+            _ => Cow::Owned(record.target().to_owned()),
+        };
+
+        Self {
+            time: Utc::now(),
+            level: record.level(),
+            target,
+            module_path,
+            file: record
+                .file_static()
+                .map(Cow::Borrowed)
+                .or_else(|| record.file().map(|f| Cow::Owned(f.to_owned()))),
+            line: record.line(),
+            thread: current_thread(),
+            args: match record.args().as_str() {
+                Some(args) => Cow::Borrowed(args),
+                None => Cow::Owned(record.args().to_string()),
+            },
+            #[cfg(feature = "kv")]
+            key_values: KeyValues::capture(record.key_values()),
+        }
+    }
+
+    /// Call `f` with the rebuilt `log::Record`, `current_time` and `current_thread_name` return the captured ones inside `f`
+    pub fn with_record<F, U>(&self, f: F) -> U
+    where
+        F: FnOnce(&Record) -> U,
+    {
+        self.scope(|| {
+            let mut builder = Record::builder();
+            builder
+                .level(self.level)
+                .target(&self.target)
+                .module_path(self.module_path.as_deref())
+                .file(self.file.as_deref())
+                .line(self.line);
+            #[cfg(feature = "kv")]
+            builder.key_values(&self.key_values);
+
+            f(&builder.args(format_args!("{}", self.args)).build())
+        })
+    }
+
+    /// Call `f`, `current_time` and `current_thread_name` return the captured ones inside `f`
+    pub fn scope<F, U>(&self, f: F) -> U
+    where
+        F: FnOnce() -> U,
+    {
+        struct Reset(*const OwnedRecord);
+        impl Drop for Reset {
+            fn drop(&mut self) {
+                CAPTURED.with(|c| c.set(self.0));
+            }
+        }
+
+        let _reset = Reset(CAPTURED.with(|c| c.replace(self as _)));
+        f()
+    }
+
+    pub(crate) fn captured<F, U>(f: F) -> Option<U>
+    where
+        F: FnOnce(&OwnedRecord) -> U,
+    {
+        let captured = CAPTURED.with(|c| c.get());
+        // the pointer only lives inside `scope`
+        unsafe { captured.as_ref() }.map(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BaseFormater, Formater};
+    use std::thread;
+
+    fn check(record: &Record) {
+        let formater = BaseFormater::new().local(true);
+        let owned = OwnedRecord::capture(record);
+        let expected = owned.scope(|| formater.format(record));
+        assert!(expected.contains(&format!("[{}]", owned.thread)));
+
+        let deferred = thread::spawn(move || owned.with_record(|r| formater.format(r)));
+        assert_eq!(deferred.join().unwrap(), expected);
+    }
+
+    #[test]
+    fn deferred_format_is_identical() {
+        let answer = 42;
+        check(
+            &Record::builder()
+                .args(format_args!("the answer is {}", answer))
+                .level(Level::Info)
+                .target("target")
+                .module_path_static(Some("nonblock_logger::record"))
+                .file_static(Some("src/record.rs"))
+                .line(Some(42))
+                .build(),
+        );
+    }
+}
@@ -0,0 +1,954 @@
+use crate::{Error, Outputer};
+use chrono::format::{parse, Parsed, StrftimeItems};
+use chrono::{DateTime, Duration, Local, NaiveDateTime, TimeZone, Timelike, Utc};
+use crossbeam_channel as channel;
+use std::cmp::Reverse;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::{self, SystemTime};
+
+#[cfg(feature = "gzip")]
+use flate2::{write::GzEncoder, Compression};
+#[cfg(feature = "gzip")]
+use std::{
+    io::BufWriter,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+/// A file `Outputer` opened by its path, which can be reopened after the file moved by logrotate
+pub struct LogFile {
+    path: PathBuf,
+    desc: String,
+    file: Option<File>,
+}
+
+impl LogFile {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        let path = path.into();
+        let desc = path.display().to_string();
+
+        Self { path, desc, file: None }
+    }
+
+    #[inline]
+    pub fn path_get(&self) -> &Path {
+        &self.path
+    }
+
+    fn open(&mut self) -> io::Result<&mut File> {
+        if self.file.is_none() {
+            self.file = Some(OpenOptions::new().create(true).append(true).open(&self.path)?);
+        }
+
+        Ok(self.file.as_mut().unwrap())
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.open()?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.as_mut().map(|f| f.flush()).unwrap_or(Ok(()))
+    }
+}
+
+impl Outputer for LogFile {
+    fn boxed(mut self) -> Result<Box<dyn Outputer>, Error> {
+        self.open()?;
+        Ok(Box::new(self) as _)
+    }
+
+    fn desc(&self) -> &str {
+        &self.desc
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        self.file = None;
+        self.open().map(|_| ())
+    }
+}
+
+/// A file `Outputer` which rolls `app.log` to `app.log.1`, `app.log.2`, ... once it reaches `max_size` bytes,
+/// it runs on the consumer thread, so the callers of `log!` never block on the rotation.
+pub struct RotatingFile {
+    path: PathBuf,
+    desc: String,
+    file: Option<File>,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+    retention: Option<Retention>,
+    errors: Errors,
+    #[cfg(feature = "gzip")]
+    gzip: Gzip,
+}
+
+impl RotatingFile {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        let path = path.into();
+        let desc = path.display().to_string();
+
+        Self {
+            path,
+            desc,
+            file: None,
+            size: 0,
+            max_size: 10 * 1024 * 1024,
+            max_files: 5,
+            retention: None,
+            errors: Errors::default(),
+            #[cfg(feature = "gzip")]
+            gzip: Gzip::default(),
+        }
+    }
+
+    /// the maximum bytes of the current file, 10MiB default
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.max_size = bytes;
+        self
+    }
+
+    #[inline]
+    pub fn max_size_get(&self) -> u64 {
+        self.max_size
+    }
+
+    /// the maximum count of the old files, 5 default, 0 means truncate the current file
+    pub fn max_files(mut self, files: usize) -> Self {
+        self.max_files = files;
+        self
+    }
+
+    #[inline]
+    pub fn max_files_get(&self) -> usize {
+        self.max_files
+    }
+
+    /// clean the old files after each rotation
+    pub fn retention(mut self, retention: Retention) -> Self {
+        self.retention = Some(retention);
+        self
+    }
+
+    /// gzip the old file to `app.log.1.gz` on a helper thread after each rotation
+    #[cfg(feature = "gzip")]
+    pub fn gzip(mut self, gzip: bool) -> Self {
+        self.gzip.enabled = gzip;
+        self
+    }
+
+    #[inline]
+    pub fn path_get(&self) -> &Path {
+        &self.path
+    }
+
+    #[cfg(feature = "gzip")]
+    fn gzipping(&self) -> bool {
+        self.gzip.enabled
+    }
+
+    #[cfg(not(feature = "gzip"))]
+    fn gzipping(&self) -> bool {
+        false
+    }
+
+    fn open(&mut self) -> io::Result<&mut File> {
+        if self.file.is_none() {
+            let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+            self.size = file.metadata()?.len();
+            self.file = Some(file);
+        }
+
+        Ok(self.file.as_mut().unwrap())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+
+        if self.max_files == 0 {
+            remove_file(&self.path)?;
+        } else if self.gzipping() {
+            #[cfg(feature = "gzip")]
+            self.rotate_gzip()?;
+        } else {
+            shift(&self.path, self.max_files)?;
+            fs::rename(&self.path, rotated(&self.path, 1, ""))?;
+            clean(&self.path, self.retention.as_ref(), &self.errors);
+        }
+
+        self.open()?;
+        Ok(())
+    }
+
+    // the old file is compressed aside as `app.log.gzipping-<nanos>`, then the helper thread shifts it in as `app.log.1.gz`,
+    // so the old files are never renamed under the compression
+    #[cfg(feature = "gzip")]
+    fn rotate_gzip(&mut self) -> io::Result<()> {
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        let mut staged = self.path.clone().into_os_string();
+        staged.push(format!(".gzipping-{}", nanos));
+        fs::rename(&self.path, &staged)?;
+
+        let (path, max_files, retention, errors) =
+            (self.path.clone(), self.max_files, self.retention.clone(), self.errors.clone());
+        self.gzip.compress(staged.into(), &self.errors, move |old| {
+            let ext = if old.extension() == Some("gz".as_ref()) { ".gz" } else { "" };
+            shift(&path, max_files)
+                .and_then(|_| fs::rename(old, rotated(&path, 1, ext)))
+                .unwrap_or_else(|e| errors.push("failed to rotate the old files", e));
+            clean(&path, retention.as_ref(), &errors);
+        });
+        Ok(())
+    }
+}
+
+// the extensions of the old files
+const EXTS: [&str; 2] = ["", ".gz"];
+
+fn rotated(path: &Path, idx: usize, ext: &str) -> PathBuf {
+    let mut path = path.to_owned().into_os_string();
+    path.push(format!(".{}{}", idx, ext));
+    path.into()
+}
+
+// shift `app.log.1` to `app.log.2` and so on, removes the last one
+fn shift(path: &Path, max_files: usize) -> io::Result<()> {
+    for ext in EXTS {
+        remove_file(&rotated(path, max_files, ext))?;
+    }
+    for idx in (1..max_files).rev() {
+        for ext in EXTS {
+            let from = rotated(path, idx, ext);
+            if from.exists() {
+                fs::rename(from, rotated(path, idx + 1, ext))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn clean(path: &Path, retention: Option<&Retention>, errors: &Errors) {
+    if let Some(retention) = retention {
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        retention
+            .sweep(path, |n| is_rotated(n, name))
+            .unwrap_or_else(|e| errors.push("failed to clean the old files", e.into()))
+    }
+}
+
+// `app.log.1` or `app.log.1.gz`, but not `app.log.bak`
+fn is_rotated(name: &str, current: &str) -> bool {
+    name.strip_prefix(current)
+        .and_then(|n| n.strip_prefix('.'))
+        .map(|idx| idx.strip_suffix(".gz").unwrap_or(idx))
+        .map(|idx| !idx.is_empty() && idx.bytes().all(|b| b.is_ascii_digit()))
+        .unwrap_or(false)
+}
+
+fn remove_file(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        let size = self.open()?.write(buf)?;
+        self.size += size as u64;
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.as_mut().map(|f| f.flush()).unwrap_or(Ok(()))
+    }
+}
+
+impl Outputer for RotatingFile {
+    fn boxed(mut self) -> Result<Box<dyn Outputer>, Error> {
+        if self.max_size == 0 {
+            Err("RotatingFile's max_size is 0")?;
+        }
+
+        self.open()?;
+        Ok(Box::new(self) as _)
+    }
+
+    fn desc(&self) -> &str {
+        &self.desc
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        self.file = None;
+        self.open().map(|_| ())
+    }
+
+    fn take_error(&mut self) -> Option<io::Error> {
+        self.errors.take()
+    }
+}
+
+/// When `TimeRotatingFile` starts a fresh file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    Hourly,
+    Daily,
+}
+
+impl Rotation {
+    fn next(self, datetime: NaiveDateTime) -> NaiveDateTime {
+        let date = datetime.date();
+        match self {
+            Rotation::Hourly => date.and_hms_opt(datetime.hour(), 0, 0).unwrap() + Duration::hours(1),
+            Rotation::Daily => date.and_hms_opt(0, 0, 0).unwrap() + Duration::days(1),
+        }
+    }
+}
+
+/// A file `Outputer` which starts a fresh file on the hour or at midnight,
+/// the file names are formatted from a chrono pattern such as `app-%Y-%m-%d.log`.
+pub struct TimeRotatingFile {
+    pattern: String,
+    rotation: Rotation,
+    local: bool,
+    path: PathBuf,
+    file: Option<File>,
+    next: DateTime<Utc>,
+    retention: Option<Retention>,
+    errors: Errors,
+    #[cfg(feature = "gzip")]
+    gzip: Gzip,
+}
+
+impl TimeRotatingFile {
+    pub fn new<S: Into<String>>(pattern: S, rotation: Rotation) -> Self {
+        Self {
+            pattern: pattern.into(),
+            rotation,
+            local: false,
+            path: PathBuf::new(),
+            file: None,
+            next: Utc::now(),
+            retention: None,
+            errors: Errors::default(),
+            #[cfg(feature = "gzip")]
+            gzip: Gzip::default(),
+        }
+    }
+
+    /// use local time for the file names and boundaries, same as `BaseFormater::local`
+    pub fn local(mut self, local: bool) -> Self {
+        self.local = local;
+        self
+    }
+
+    #[inline]
+    pub fn local_get(&self) -> bool {
+        self.local
+    }
+
+    #[inline]
+    pub fn pattern_get(&self) -> &str {
+        &self.pattern
+    }
+
+    #[inline]
+    pub fn rotation_get(&self) -> Rotation {
+        self.rotation
+    }
+
+    /// clean the old files after each rotation, only the files in the directory of the current file are checked
+    pub fn retention(mut self, retention: Retention) -> Self {
+        self.retention = Some(retention);
+        self
+    }
+
+    /// gzip the old file to `app-2020-02-29.log.gz` on a helper thread after each rotation
+    #[cfg(feature = "gzip")]
+    pub fn gzip(mut self, gzip: bool) -> Self {
+        self.gzip.enabled = gzip;
+        self
+    }
+
+    /// the path of the current file, empty before the first message
+    #[inline]
+    pub fn path_get(&self) -> &Path {
+        &self.path
+    }
+
+    fn next_boundary(&self, now: &DateTime<Utc>) -> DateTime<Utc> {
+        if !self.local {
+            return Utc.from_utc_datetime(&self.rotation.next(now.naive_utc()));
+        }
+
+        let local = now.with_timezone(&Local);
+        let next = self.rotation.next(local.naive_local());
+        Local
+            .from_local_datetime(&next)
+            .earliest()
+            .map(|dt| dt.with_timezone(&Utc))
+            // skipped by daylight saving time, keep the current offset
+            .unwrap_or_else(|| Utc.from_utc_datetime(&(next - Duration::seconds(local.offset().local_minus_utc() as _))))
+    }
+
+    fn roll(&mut self, now: &DateTime<Utc>) -> io::Result<()> {
+        let path = if self.local {
+            now.with_timezone(&Local).format(&self.pattern).to_string()
+        } else {
+            now.format(&self.pattern).to_string()
+        };
+
+        let path = PathBuf::from(path);
+        let old = mem::replace(&mut self.path, path);
+        if self.file.take().is_some() && old != self.path {
+            #[cfg(feature = "gzip")]
+            if self.gzip.enabled {
+                self.gzip.compress(old, &self.errors, |_| {});
+            }
+        }
+
+        if let Some(parent) = self.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+
+        self.file = Some(OpenOptions::new().create(true).append(true).open(&self.path)?);
+        self.next = self.next_boundary(now);
+        self.clean();
+        Ok(())
+    }
+
+    fn clean(&self) {
+        if let Some(retention) = self.retention.as_ref() {
+            let name = Path::new(&self.pattern)
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or_default();
+            #[cfg(feature = "gzip")]
+            let compressing = |n: &str| self.gzip.compressing(n);
+            #[cfg(not(feature = "gzip"))]
+            let compressing = |_: &str| false;
+
+            retention
+                .sweep(&self.path, |n| {
+                    (formatted(n, name) || n.strip_suffix(".gz").map(|n| formatted(n, name)).unwrap_or(false))
+                        && !compressing(n)
+                })
+                .unwrap_or_else(|e| self.errors.push("failed to clean the old files", e.into()))
+        }
+    }
+}
+
+impl Write for TimeRotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let now = Utc::now();
+        if self.file.is_none() || now >= self.next {
+            self.roll(&now)?;
+        }
+
+        self.file.as_mut().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.as_mut().map(|f| f.flush()).unwrap_or(Ok(()))
+    }
+}
+
+impl Outputer for TimeRotatingFile {
+    fn boxed(mut self) -> Result<Box<dyn Outputer>, Error> {
+        if self.pattern.is_empty() {
+            Err("TimeRotatingFile's pattern is empty")?;
+        }
+
+        self.roll(&Utc::now())?;
+        Ok(Box::new(self) as _)
+    }
+
+    fn desc(&self) -> &str {
+        &self.pattern
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        self.file = None;
+        self.file = Some(OpenOptions::new().create(true).append(true).open(&self.path)?);
+        Ok(())
+    }
+
+    fn take_error(&mut self) -> Option<io::Error> {
+        self.errors.take()
+    }
+}
+
+// the errors off the writes, such as cleaning or compressing the old files, taken by `Outputer::take_error`
+#[derive(Debug, Clone)]
+struct Errors(channel::Sender<io::Error>, channel::Receiver<io::Error>);
+
+impl Default for Errors {
+    fn default() -> Self {
+        let (mp, mc) = channel::unbounded();
+        Errors(mp, mc)
+    }
+}
+
+impl Errors {
+    fn push(&self, context: &str, e: io::Error) {
+        self.0.send(io::Error::new(e.kind(), format!("{}: {}", context, e))).ok();
+    }
+
+    fn take(&self) -> Option<io::Error> {
+        self.1.try_recv().ok()
+    }
+}
+
+// compress the old files on a helper thread, so the consumer thread keeps draining the channel
+#[cfg(feature = "gzip")]
+#[derive(Default)]
+struct Gzip {
+    enabled: bool,
+    // the queue of the helper thread, started by the first job
+    helper: Option<(channel::Sender<Job>, thread::JoinHandle<()>)>,
+    // the files queued or being compressed
+    pending: Arc<Mutex<Vec<PathBuf>>>,
+}
+
+// the file to compress, and what to do with the `.gz`, or with the file itself if it failed
+#[cfg(feature = "gzip")]
+type Job = (PathBuf, Box<dyn FnOnce(PathBuf) + Send>);
+
+#[cfg(feature = "gzip")]
+impl Gzip {
+    // the files being compressed or their `.gz`, which are kept from the retention
+    fn compressing(&self, name: &str) -> bool {
+        let name = name.strip_suffix(".gz").unwrap_or(name);
+        self.pending
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|path| path.file_name().and_then(|n| n.to_str()) == Some(name))
+    }
+
+    fn compress<F>(&mut self, path: PathBuf, errors: &Errors, then: F)
+    where
+        F: FnOnce(PathBuf) + Send + 'static,
+    {
+        self.pending.lock().unwrap().push(path.clone());
+        let job: Job = (path, Box::new(then));
+
+        if self.helper.is_none() {
+            let (mp, mc) = channel::unbounded::<Job>();
+            let (pending, reported) = (self.pending.clone(), errors.clone());
+            match thread::Builder::new()
+                .name("log-gzip".into())
+                .spawn(move || mc.into_iter().for_each(|job| run(job, &pending, &reported)))
+            {
+                Ok(h) => self.helper = Some((mp, h)),
+                Err(e) => {
+                    errors.push("failed to spawn the gzip thread", e);
+                    return run(job, &self.pending, errors);
+                }
+            }
+        }
+
+        self.helper.as_ref().unwrap().0.send(job).ok();
+    }
+}
+
+#[cfg(feature = "gzip")]
+fn run((path, then): Job, pending: &Mutex<Vec<PathBuf>>, errors: &Errors) {
+    let done = gzip(&path).unwrap_or_else(|e| {
+        errors.push(&format!("failed to gzip {}", path.display()), e);
+        path.clone()
+    });
+    then(done);
+    pending.lock().unwrap().retain(|p| p != &path);
+}
+
+#[cfg(feature = "gzip")]
+impl Drop for Gzip {
+    // finish the queued jobs
+    fn drop(&mut self) {
+        if let Some((mp, h)) = self.helper.take() {
+            drop(mp);
+            h.join().ok();
+        }
+    }
+}
+
+#[cfg(feature = "gzip")]
+fn gzip(path: &Path) -> io::Result<PathBuf> {
+    let mut gz = path.to_owned().into_os_string();
+    gz.push(".gz");
+
+    let mut file = File::open(path)?;
+    let mut encoder = GzEncoder::new(BufWriter::new(File::create(&gz)?), Compression::default());
+    io::copy(&mut file, &mut encoder)?;
+    encoder.finish()?.flush()?;
+
+    fs::remove_file(path)?;
+    Ok(gz.into())
+}
+
+/// Deletes the rotated files older than `max_age`, or beyond the `max_size` budget from the oldest,
+/// it runs on the consumer thread after each rotation, or on the gzip helper thread once the old file compressed.
+#[derive(Debug, Clone, Default)]
+pub struct Retention {
+    max_age: Option<time::Duration>,
+    max_size: Option<u64>,
+}
+
+impl Retention {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn max_age(mut self, age: time::Duration) -> Self {
+        self.max_age = Some(age);
+        self
+    }
+
+    pub fn max_days(self, days: u64) -> Self {
+        self.max_age(time::Duration::from_secs(days * 24 * 60 * 60))
+    }
+
+    #[inline]
+    pub fn max_age_get(&self) -> Option<time::Duration> {
+        self.max_age
+    }
+
+    /// the maximum total bytes of the rotated files, the current file is not counted
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    #[inline]
+    pub fn max_size_get(&self) -> Option<u64> {
+        self.max_size
+    }
+
+    /// sweep the files beside `current` which names match `rotated`, keep going on errors and return the first one
+    pub fn sweep<F>(&self, current: &Path, rotated: F) -> Result<(), Error>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let dir = current
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        let now = SystemTime::now();
+        let mut error = None;
+
+        let mut files = vec![];
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.file_name() == current.file_name() || !entry.file_name().to_str().map(&rotated).unwrap_or(false) {
+                continue;
+            }
+
+            match entry.metadata() {
+                Ok(meta) if meta.is_file() => files.push((meta.modified().unwrap_or(now), meta.len(), path)),
+                Ok(_) => {}
+                Err(e) => error = error.or(Some(e)),
+            }
+        }
+
+        // newest first
+        files.sort_by_key(|f| Reverse(f.0));
+
+        let mut size = 0;
+        for (modified, len, path) in files {
+            size += len;
+            let expired = self
+                .max_age
+                .map(|age| now.duration_since(modified).map(|d| d > age).unwrap_or(false))
+                .unwrap_or(false);
+            let oversize = self.max_size.map(|max| size > max).unwrap_or(false);
+
+            if expired || oversize {
+                if let Err(e) = remove_file(&path) {
+                    error = error.or(Some(e));
+                }
+            }
+        }
+
+        match error {
+            Some(e) => Err(e.into()),
+            None => Ok(()),
+        }
+    }
+}
+
+// whether the file name is formatted from the chrono pattern, `app-%Y-%m-%d.log` takes `app-2020-02-29.log` only
+fn formatted(name: &str, pattern: &str) -> bool {
+    parse(&mut Parsed::new(), name, StrftimeItems::new(pattern)).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    #[test]
+    fn log_file_reopens_moved_file() {
+        let dir = env::temp_dir().join(format!("nonblock-logger-logfile-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.log");
+
+        let mut file = LogFile::new(&path).boxed().unwrap();
+        file.write_all(b"1\n").unwrap();
+        // moved by logrotate
+        fs::rename(&path, dir.join("app.log.1")).unwrap();
+        file.write_all(b"2\n").unwrap();
+        file.reopen().unwrap();
+        file.write_all(b"3\n").unwrap();
+
+        assert_eq!(fs::read_to_string(dir.join("app.log.1")).unwrap(), "1\n2\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "3\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotating_file_keeps_max_files() {
+        let dir = env::temp_dir().join(format!("nonblock-logger-rotating-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.log");
+
+        let mut file = RotatingFile::new(&path).max_size(10).max_files(2);
+        for idx in 0..4 {
+            file.write_all(format!("{:09}\n", idx).as_bytes()).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "000000003\n");
+        assert_eq!(fs::read_to_string(dir.join("app.log.1")).unwrap(), "000000002\n");
+        assert_eq!(fs::read_to_string(dir.join("app.log.2")).unwrap(), "000000001\n");
+        assert!(!dir.join("app.log.3").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotating_file_keeps_the_unrelated_files() {
+        let dir = env::temp_dir().join(format!("nonblock-logger-siblings-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.log");
+        for name in ["app.log.spool", "app.log.bak", "app.log.1x", "app.log.", "app.log.9"].iter() {
+            fs::write(dir.join(name), "x\n").unwrap();
+        }
+
+        let mut file = RotatingFile::new(&path)
+            .max_size(10)
+            .max_files(2)
+            .retention(Retention::new().max_size(0));
+        for idx in 0..2 {
+            file.write_all(format!("{:09}\n", idx).as_bytes()).unwrap();
+        }
+
+        assert!(!dir.join("app.log.1").exists() && !dir.join("app.log.9").exists());
+        for name in ["app.log.spool", "app.log.bak", "app.log.1x", "app.log."].iter() {
+            assert!(dir.join(name).exists(), "{}", name);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn rotating_file_gzips_old_files() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let dir = env::temp_dir().join(format!("nonblock-logger-gzip-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.log");
+
+        let mut file = RotatingFile::new(&path).max_size(10).max_files(2).gzip(true);
+        for idx in 0..4 {
+            file.write_all(format!("{:09}\n", idx).as_bytes()).unwrap();
+        }
+        // finish the queued jobs
+        drop(file);
+
+        let gunzip = |name: &str| {
+            let mut content = String::new();
+            GzDecoder::new(File::open(dir.join(name)).unwrap())
+                .read_to_string(&mut content)
+                .unwrap();
+            content
+        };
+        assert_eq!(fs::read_to_string(&path).unwrap(), "000000003\n");
+        assert_eq!(gunzip("app.log.1.gz"), "000000002\n");
+        assert_eq!(gunzip("app.log.2.gz"), "000000001\n");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+
+        // the retention runs once the old file compressed
+        let mut file = RotatingFile::new(&path)
+            .max_size(10)
+            .gzip(true)
+            .retention(Retention::new().max_size(0))
+            .boxed()
+            .unwrap();
+        file.write_all(b"000000004\n").unwrap();
+        drop(file);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn time_rotating_file_next_boundary() {
+        let now = Utc.with_ymd_and_hms(2020, 2, 29, 23, 59, 59).unwrap();
+
+        let hourly = TimeRotatingFile::new("app-%Y-%m-%d-%H.log", Rotation::Hourly);
+        assert_eq!(hourly.next_boundary(&now), Utc.with_ymd_and_hms(2020, 3, 1, 0, 0, 0).unwrap());
+
+        let now = Utc.with_ymd_and_hms(2020, 2, 29, 8, 30, 0).unwrap();
+        let daily = TimeRotatingFile::new("app-%Y-%m-%d.log", Rotation::Daily);
+        assert_eq!(daily.next_boundary(&now), Utc.with_ymd_and_hms(2020, 3, 1, 0, 0, 0).unwrap());
+        assert_eq!(
+            hourly.next_boundary(&now),
+            Utc.with_ymd_and_hms(2020, 2, 29, 9, 0, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn retention_sweeps_expired_and_oversize() {
+        let dir = env::temp_dir().join(format!("nonblock-logger-retention-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.log");
+
+        let now = SystemTime::now();
+        let hour = time::Duration::from_secs(60 * 60);
+        for (name, age) in [
+            ("app.log", 0),
+            ("app.log.1", 1),
+            ("app.log.2", 3),
+            ("app.log.3", 48),
+            ("other.log", 48),
+        ]
+        .iter()
+        {
+            let file = File::create(dir.join(name)).unwrap();
+            file.set_len(10).unwrap();
+            file.set_modified(now - hour * *age).unwrap();
+        }
+        let rotated = |n: &str| n.starts_with("app.log.");
+        let exists = |name: &str| dir.join(name).exists();
+
+        Retention::new().max_days(1).sweep(&path, rotated).unwrap();
+        assert!(exists("app.log.1") && exists("app.log.2") && !exists("app.log.3"));
+        assert!(exists("other.log"));
+
+        // the newest within the budget are kept, the current file is not counted
+        Retention::new().max_size(15).sweep(&path, rotated).unwrap();
+        assert!(exists("app.log") && exists("app.log.1") && !exists("app.log.2"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn time_rotating_file_writes_the_new_file() {
+        let dir = env::temp_dir().join(format!("nonblock-logger-time-{}", std::process::id()));
+        let pattern = dir.join("app-%Y-%m-%d-%H.log").to_str().unwrap().to_owned();
+
+        let mut file = TimeRotatingFile::new(pattern.as_str(), Rotation::Hourly);
+        file.roll(&Utc.with_ymd_and_hms(2020, 2, 29, 8, 30, 0).unwrap()).unwrap();
+        assert_eq!(file.next, Utc.with_ymd_and_hms(2020, 2, 29, 9, 0, 0).unwrap());
+        // keep writing the file till the boundary passed
+        file.next = Utc::now() + Duration::hours(1);
+        file.write_all(b"1\n").unwrap();
+        file.next = Utc.with_ymd_and_hms(2020, 2, 29, 9, 0, 0).unwrap();
+
+        let before = Utc::now().format(&pattern).to_string();
+        file.write_all(b"2\n").unwrap();
+        let after = Utc::now().format(&pattern).to_string();
+
+        let path = file.path_get().to_str().unwrap().to_owned();
+        assert!(path == before || path == after, "{}", path);
+        assert!(file.next > Utc::now());
+        assert_eq!(fs::read_to_string(dir.join("app-2020-02-29-08.log")).unwrap(), "1\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "2\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn time_rotating_file_local_time() {
+        // rerun in a zone half an hour off UTC, so the zone of the other tests stays
+        let tz = "Asia/Kolkata";
+        if env::var("TZ").ok().as_deref() != Some(tz) {
+            let status = std::process::Command::new(env::current_exe().unwrap())
+                .args(["rotate::tests::time_rotating_file_local_time", "--exact", "--quiet"].iter())
+                .env("TZ", tz)
+                .status()
+                .unwrap();
+            assert!(status.success());
+            return;
+        }
+
+        let dir = env::temp_dir().join(format!("nonblock-logger-local-{}", std::process::id()));
+        let pattern = dir.join("app-%Y-%m-%d-%H.log").to_str().unwrap().to_owned();
+        let now = Utc.with_ymd_and_hms(2020, 2, 29, 8, 10, 0).unwrap();
+
+        let mut hourly = TimeRotatingFile::new(pattern.as_str(), Rotation::Hourly).local(true);
+        hourly.roll(&now).unwrap();
+        assert_eq!(hourly.path_get(), dir.join("app-2020-02-29-13.log"));
+        assert_eq!(hourly.next, Utc.with_ymd_and_hms(2020, 2, 29, 8, 30, 0).unwrap());
+
+        let daily = TimeRotatingFile::new(pattern.as_str(), Rotation::Daily).local(true);
+        assert_eq!(
+            daily.next_boundary(&now),
+            Utc.with_ymd_and_hms(2020, 2, 29, 18, 30, 0).unwrap()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn time_rotating_file_pattern_matches() {
+        let pattern = "app-%Y-%m-%d_%-H.log";
+        assert!(formatted("app-2020-02-29_8.log", pattern));
+        assert!(!formatted("app-2020-02-29.log", pattern));
+        assert!(!formatted("app-2020-02-29_8.log.bak", pattern));
+        assert!(!formatted("app-backup-2020-02-29_8.log", pattern));
+    }
+
+    #[test]
+    fn time_rotating_file_keeps_the_unrelated_files() {
+        let dir = env::temp_dir().join(format!("nonblock-logger-time-siblings-{}", std::process::id()));
+        let pattern = dir.join("app-%Y-%m-%d.log").to_str().unwrap().to_owned();
+        fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "app-2020-02-28.log",
+            "app-2020-02-28.log.gz",
+            "app-2020-02-28.log.spool",
+            "app-old.log",
+        ]
+        .iter()
+        {
+            fs::write(dir.join(name), "x\n").unwrap();
+        }
+
+        let mut file = TimeRotatingFile::new(pattern.as_str(), Rotation::Daily).retention(Retention::new().max_size(0));
+        file.roll(&Utc.with_ymd_and_hms(2020, 2, 29, 8, 0, 0).unwrap()).unwrap();
+
+        let exists = |name: &str| dir.join(name).exists();
+        assert!(exists("app-2020-02-29.log"));
+        assert!(!exists("app-2020-02-28.log") && !exists("app-2020-02-28.log.gz"));
+        assert!(exists("app-2020-02-28.log.spool") && exists("app-old.log"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,442 @@
+use crate::{Event, Message, OwnedRecord};
+use chrono::DateTime;
+use crossbeam_channel as channel;
+use log::Level;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The file the overflowed messages are appended to, replayed in order by `Receiver` once the channel is caught up,
+/// the messages left by a previous run are replayed first
+#[derive(Debug)]
+pub(crate) struct Spool {
+    path: PathBuf,
+    // set while the spool has messages, the later ones are appended to it too to keep the order
+    pending: AtomicBool,
+    // the messages not replayed yet
+    len: AtomicUsize,
+    // opened by `recover` or the first spilled message
+    files: Mutex<Option<Files>>,
+    // wakes the consumer thread waiting for the channel, after pushed
+    wake: (channel::Sender<()>, channel::Receiver<()>),
+}
+
+#[derive(Debug)]
+struct Files {
+    writer: File,
+    reader: BufReader<File>,
+    // the offsets of the end and the replayed
+    end: u64,
+    read: u64,
+}
+
+impl Files {
+    // keep the messages left in the file, returns the count of them, a partial one at the end is cut
+    fn open(path: &Path) -> io::Result<(Self, usize)> {
+        let writer = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let size = writer.metadata()?.len();
+        let (mut end, mut count) = (0, 0);
+        let mut len = [0; 4];
+        while end + 4 <= size {
+            reader.seek(SeekFrom::Start(end))?;
+            reader.read_exact(&mut len)?;
+            let next = end + 4 + u32::from_le_bytes(len) as u64;
+            if next > size {
+                break;
+            }
+            end = next;
+            count += 1;
+        }
+        writer.set_len(end)?;
+        reader.seek(SeekFrom::Start(0))?;
+
+        let files = Self {
+            writer,
+            reader,
+            end,
+            read: 0,
+        };
+        Ok((files, count))
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        self.writer.set_len(0)?;
+        self.reader.seek(SeekFrom::Start(0))?;
+        self.end = 0;
+        self.read = 0;
+        Ok(())
+    }
+
+    fn next(&mut self, buf: &mut Vec<u8>) -> io::Result<Message> {
+        let mut len = [0; 4];
+        self.reader.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as usize;
+
+        buf.clear();
+        buf.resize(len, 0);
+        self.reader.read_exact(buf)?;
+        self.read += 4 + len as u64;
+
+        decode(&mut Decoder(buf)).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "corrupted spool"))
+    }
+}
+
+impl Spool {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            pending: AtomicBool::new(false),
+            len: AtomicUsize::new(0),
+            files: Mutex::new(None),
+            wake: channel::bounded(1),
+        }
+    }
+
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    #[inline]
+    pub fn pending(&self) -> bool {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// ready after the messages pushed, for the consumer thread to replay them
+    #[inline]
+    pub fn woken(&self) -> &channel::Receiver<()> {
+        &self.wake.1
+    }
+
+    /// open the file left by a previous run, its messages are replayed before the others
+    pub fn recover(&self) -> io::Result<()> {
+        if !self.path.exists() {
+            return Ok(());
+        }
+
+        let mut files = self.files.lock().unwrap();
+        if files.is_none() {
+            *files = Some(self.open()?);
+        }
+        Ok(())
+    }
+
+    fn open(&self) -> io::Result<Files> {
+        let (files, count) = Files::open(&self.path)?;
+        if count > 0 {
+            self.len.fetch_add(count, Ordering::Relaxed);
+            self.pending.store(true, Ordering::Release);
+            self.wake.0.try_send(()).ok();
+        }
+        Ok(files)
+    }
+
+    /// append the message, returns the event back if it's not a message or failed to write the file
+    pub fn push(&self, event: Event) -> Result<(), Event> {
+        let mut buf = match &event {
+            Event::Message(message) => encode(message),
+            _ => return Err(event),
+        };
+        let len = (buf.len() - 4) as u32;
+        buf[..4].copy_from_slice(&len.to_le_bytes());
+
+        let mut files = self.files.lock().unwrap();
+        if files.is_none() {
+            *files = self.open().ok();
+        }
+        let files = match files.as_mut() {
+            Some(files) => files,
+            None => return Err(event),
+        };
+
+        if files.writer.write_all(&buf).is_err() {
+            // cut the partial message
+            files.writer.set_len(files.end).ok();
+            return Err(event);
+        }
+
+        files.end += buf.len() as u64;
+        self.len.fetch_add(1, Ordering::Relaxed);
+        self.pending.store(true, Ordering::Release);
+
+        // one is enough if not received yet
+        self.wake.0.try_send(()).ok();
+        Ok(())
+    }
+
+    /// read up to `max` messages into `replayed` if `caught_up()`, returns the count of the lost messages if the spool is broken
+    pub fn replay<F>(&self, caught_up: F, max: Option<usize>, replayed: &mut VecDeque<Event>) -> usize
+    where
+        F: FnOnce() -> bool,
+    {
+        let mut files = self.files.lock().unwrap();
+        // checked under the lock, the messages sent to the channel before spooled are all received
+        let files = match files.as_mut() {
+            Some(files) if self.pending() && caught_up() => files,
+            _ => return 0,
+        };
+
+        let mut buf = Vec::new();
+        let mut count = 0;
+        while files.read < files.end && max.map(|max| count < max).unwrap_or(true) {
+            match files.next(&mut buf) {
+                Ok(message) => replayed.push_back(Event::Message(message)),
+                Err(_) => {
+                    files.reset().ok();
+                    self.pending.store(false, Ordering::Release);
+                    return self.len.swap(0, Ordering::Relaxed);
+                }
+            }
+
+            count += 1;
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
+
+        if files.read >= files.end {
+            files.reset().ok();
+            self.pending.store(false, Ordering::Release);
+        }
+
+        0
+    }
+}
+
+fn encode(message: &Message) -> Vec<u8> {
+    // the length is filled by `push`
+    let mut buf = Vec::with_capacity(128 + message.bytes());
+    put_u32(&mut buf, 0);
+    buf.push(message.level as u8);
+    put_str(&mut buf, &message.content);
+
+    let record = match message.record.as_ref() {
+        Some(record) => record,
+        None => {
+            buf.push(0);
+            return buf;
+        }
+    };
+
+    buf.push(1);
+    put_u64(&mut buf, record.time.timestamp() as u64);
+    put_u32(&mut buf, record.time.timestamp_subsec_nanos());
+    put_str(&mut buf, &record.target);
+    put_opt_str(&mut buf, record.module_path.as_deref());
+    put_opt_str(&mut buf, record.file.as_deref());
+    match record.line {
+        Some(line) => {
+            buf.push(1);
+            put_u32(&mut buf, line);
+        }
+        None => buf.push(0),
+    }
+    put_str(&mut buf, &record.thread);
+    put_str(&mut buf, &record.args);
+    #[cfg(feature = "kv")]
+    record.key_values.encode(&mut buf);
+
+    buf
+}
+
+fn decode(dec: &mut Decoder) -> Option<Message> {
+    let level = match dec.u8()? {
+        1 => Level::Error,
+        2 => Level::Warn,
+        3 => Level::Info,
+        4 => Level::Debug,
+        5 => Level::Trace,
+        _ => return None,
+    };
+    let content = dec.string()?;
+
+    if dec.u8()? == 0 {
+        return Some(Message::new(content, level));
+    }
+
+    let record = OwnedRecord {
+        time: DateTime::from_timestamp(dec.u64()? as i64, dec.u32()?)?,
+        level,
+        target: Cow::Owned(dec.string()?),
+        module_path: dec.opt_string()?.map(Cow::Owned),
+        file: dec.opt_string()?.map(Cow::Owned),
+        line: match dec.u8()? {
+            0 => None,
+            _ => Some(dec.u32()?),
+        },
+        thread: Arc::from(dec.string()?),
+        args: Cow::Owned(dec.string()?),
+        #[cfg(feature = "kv")]
+        key_values: crate::KeyValues::decode(dec)?,
+    };
+
+    Some(Message::structured(content, record))
+}
+
+pub(crate) fn put_u32(buf: &mut Vec<u8>, u: u32) {
+    buf.extend_from_slice(&u.to_le_bytes());
+}
+
+pub(crate) fn put_u64(buf: &mut Vec<u8>, u: u64) {
+    buf.extend_from_slice(&u.to_le_bytes());
+}
+
+pub(crate) fn put_str(buf: &mut Vec<u8>, s: &str) {
+    put_u32(buf, s.len() as u32);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn put_opt_str(buf: &mut Vec<u8>, s: Option<&str>) {
+    match s {
+        Some(s) => {
+            buf.push(1);
+            put_str(buf, s);
+        }
+        None => buf.push(0),
+    }
+}
+
+pub(crate) struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    pub fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        self.bytes(4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        let mut u = [0; 8];
+        u.copy_from_slice(self.bytes(8)?);
+        Some(u64::from_le_bytes(u))
+    }
+
+    pub fn string(&mut self) -> Option<String> {
+        let len = self.u32()? as usize;
+        self.bytes(len).and_then(|b| String::from_utf8(b.to_vec()).ok())
+    }
+
+    fn opt_string(&mut self) -> Option<Option<String>> {
+        match self.u8()? {
+            0 => Some(None),
+            _ => self.string().map(Some),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Record;
+
+    #[test]
+    fn spool_replays_in_order() {
+        let path = std::env::temp_dir().join(format!("nonblock-logger-spool-{}", std::process::id()));
+        let spool = Spool::new(path.clone());
+
+        let record = Record::builder()
+            .args(format_args!("deferred"))
+            .level(Level::Warn)
+            .target("spool")
+            .line(Some(3))
+            .build();
+        let owned = OwnedRecord::capture(&record);
+        for event in [
+            Event::Message(Message::new("1\n".to_owned(), Level::Info)),
+            Event::Message(Message::deferred(owned.clone())),
+            Event::Message(Message::new("3\n".to_owned(), Level::Error)),
+        ] {
+            assert!(spool.push(event).is_ok());
+        }
+        assert!(spool.push(Event::Reopen).is_err());
+        assert_eq!(spool.len(), 3);
+
+        let mut replayed = VecDeque::new();
+        assert_eq!(spool.replay(|| false, None, &mut replayed), 0);
+        assert!(replayed.is_empty());
+
+        spool.replay(|| true, Some(2), &mut replayed);
+        assert!(spool.pending());
+        spool.replay(|| true, None, &mut replayed);
+        assert!(!spool.pending());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+
+        let messages = replayed
+            .into_iter()
+            .map(|e| match e {
+                Event::Message(m) => m,
+                e => panic!("{:?}", e),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(messages[0].content, "1\n");
+        assert_eq!(messages[2].level, Level::Error);
+
+        let deferred = messages[1].record.as_ref().unwrap();
+        assert_eq!(
+            (deferred.time, &deferred.thread, &deferred.args),
+            (owned.time, &owned.thread, &owned.args)
+        );
+        assert_eq!(
+            (deferred.target.as_ref(), deferred.line, deferred.file.as_ref()),
+            ("spool", Some(3), None)
+        );
+
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn spool_recovers_the_left_messages() {
+        let path = std::env::temp_dir().join(format!("nonblock-logger-spool-left-{}", std::process::id()));
+        let left = Spool::new(path.clone());
+        for content in ["1\n", "2\n"] {
+            assert!(left
+                .push(Event::Message(Message::new(content.to_owned(), Level::Info)))
+                .is_ok());
+        }
+        drop(left);
+        // a partial message at the end, as if crashed while writing
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[9, 0, 0, 0, 3]).unwrap();
+
+        let spool = Spool::new(path.clone());
+        spool.recover().unwrap();
+        assert!(spool.pending());
+        assert_eq!(spool.len(), 2);
+        assert!(spool
+            .push(Event::Message(Message::new("3\n".to_owned(), Level::Info)))
+            .is_ok());
+
+        let mut replayed = VecDeque::new();
+        assert_eq!(spool.replay(|| true, None, &mut replayed), 0);
+        let contents = replayed
+            .into_iter()
+            .map(|e| match e {
+                Event::Message(m) => m.content,
+                e => panic!("{:?}", e),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(contents, ["1\n", "2\n", "3\n"]);
+
+        std::fs::remove_file(path).ok();
+    }
+}
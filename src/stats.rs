@@ -0,0 +1,120 @@
+use log::Level;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The counters of `NonblockLogger`, cheap relaxed atomics
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    accepted: [AtomicU64; 5],
+    filtered: AtomicU64,
+    dropped: AtomicU64,
+    spooled: AtomicU64,
+    high_water: AtomicUsize,
+    outputers: Mutex<Vec<(String, Arc<OutputerCounters>)>>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct OutputerCounters {
+    pub written: AtomicU64,
+    pub errors: AtomicU64,
+}
+
+impl Counters {
+    #[inline]
+    pub fn accepted(&self, level: Level) {
+        self.accepted[level as usize - 1].fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn filtered(&self) {
+        self.filtered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn dropped(&self, count: u64) {
+        self.dropped.fetch_add(count, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn spooled(&self) {
+        self.spooled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn queued(&self, len: usize) {
+        if len > self.high_water.load(Ordering::Relaxed) {
+            self.high_water.fetch_max(len, Ordering::Relaxed);
+        }
+    }
+
+    pub fn register<S: Into<String>>(&self, desc: S, counters: Arc<OutputerCounters>) {
+        self.outputers.lock().unwrap().push((desc.into(), counters));
+    }
+
+    pub fn snapshot(&self) -> Stats {
+        let mut accepted = [0; 5];
+        accepted
+            .iter_mut()
+            .zip(self.accepted.iter())
+            .for_each(|(a, c)| *a = c.load(Ordering::Relaxed));
+
+        let outputers = self
+            .outputers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(desc, c)| OutputerStats {
+                desc: desc.clone(),
+                written: c.written.load(Ordering::Relaxed),
+                errors: c.errors.load(Ordering::Relaxed),
+            })
+            .collect::<Vec<_>>();
+
+        Stats {
+            accepted,
+            filtered: self.filtered.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            spooled: self.spooled.load(Ordering::Relaxed),
+            write_errors: outputers.iter().map(|o| o.errors).sum(),
+            high_water: self.high_water.load(Ordering::Relaxed),
+            outputers,
+        }
+    }
+}
+
+/// A snapshot of the counters of `NonblockLogger`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// the messages passed the filter, indexed by `Level as usize - 1`
+    pub accepted: [u64; 5],
+    /// the messages rejected by the filter
+    pub filtered: u64,
+    /// the messages dropped by `sendfn`
+    pub dropped: u64,
+    /// the messages appended to the spool file
+    pub spooled: u64,
+    /// the errors of all the outputers
+    pub write_errors: u64,
+    /// the high-water mark of the messages in channel
+    pub high_water: usize,
+    pub outputers: Vec<OutputerStats>,
+}
+
+impl Stats {
+    pub fn accepted(&self, level: Level) -> u64 {
+        self.accepted[level as usize - 1]
+    }
+
+    pub fn accepted_total(&self) -> u64 {
+        self.accepted.iter().sum()
+    }
+}
+
+/// The counters of an `Outputer` of `BaseConsumer`, keyed by its `desc()`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OutputerStats {
+    pub desc: String,
+    /// bytes written
+    pub written: u64,
+    pub errors: u64,
+}